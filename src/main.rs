@@ -1,35 +1,183 @@
 use anyhow::Result;
-use cargo_metadata::{MetadataCommand, Package};
-use clap::{Args as ClapArgs, Parser};
+use cargo_metadata::{DependencyKind, MetadataCommand, Node, Package, PackageId, Resolve};
+use clap::error::ErrorKind;
+use clap::{Args as ClapArgs, CommandFactory, Parser, ValueEnum};
 use colored::*;
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::env::current_dir;
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use notify::{EventKind, RecursiveMode, Watcher};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env::{self, current_dir};
+use std::hash::{Hash, Hasher};
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::{fs, process};
-use tracing::info;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, process, thread};
+use tracing::{info, info_span};
+use tracing_subscriber::EnvFilter;
 use which::which;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "cargo-groups configuration")]
 struct RootCargoToml {
     #[serde(default)]
     workspace: Workspace,
+    // Groups can also be defined on the root package when the root
+    // is both a package and a workspace
+    package: Option<RootPackage>,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Deserialize, JsonSchema)]
+struct RootPackage {
+    #[serde(default)]
+    metadata: Metadata,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
 struct Workspace {
+    #[serde(default)]
     metadata: Metadata,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Deserialize, JsonSchema)]
 struct Metadata {
-    groups: HashMap<String, Vec<String>>,
+    /// Groups of crates, mapping each group name to a list of patterns.
+    /// Patterns are globs prefixed with `pkg:` to match crate names,
+    /// `path:` to match crate paths relative to the workspace root, and
+    /// `deps:` or `rdeps:` to match crates by name along with the workspace
+    /// crates they depend on or that depend on them. `members` matches every workspace member
+    #[serde(default)]
+    groups: HashMap<String, GroupDefinition>,
+    /// Options applied to every command run on a group, keyed by group name
+    #[serde(default, rename = "groups-defaults")]
+    groups_defaults: HashMap<String, DefaultOptions>,
+    /// Custom commands, mapping a command name to a cargo command template.
+    /// `{packages}` in the template is replaced by the `-p` flags of the group's
+    /// crates and `{group}` by the group name
+    #[serde(default, rename = "groups-commands")]
+    groups_commands: HashMap<String, String>,
+    /// Named sets of options that can be applied to any group with `--profile-name`
+    #[serde(default, rename = "groups-profiles")]
+    groups_profiles: HashMap<String, DefaultOptions>,
+}
+
+// A group is either a list of patterns or a table with the patterns, a description
+// and the environment variables to run commands with
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum GroupDefinition {
+    Patterns(Vec<String>),
+    Table {
+        patterns: Vec<String>,
+        /// What the group is for, shown by `cargo groups list`
+        description: Option<String>,
+        /// Environment variables set for the commands run on the group
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+}
+
+impl GroupDefinition {
+    fn patterns(&self) -> &[String] {
+        match self {
+            GroupDefinition::Patterns(patterns) => patterns,
+            GroupDefinition::Table { patterns, .. } => patterns,
+        }
+    }
+
+    fn description(&self) -> Option<&str> {
+        match self {
+            GroupDefinition::Patterns(_) => None,
+            GroupDefinition::Table { description, .. } => description.as_deref(),
+        }
+    }
+
+    fn env(&self) -> Option<&BTreeMap<String, String>> {
+        match self {
+            GroupDefinition::Patterns(_) => None,
+            GroupDefinition::Table { env, .. } => Some(env),
+        }
+    }
+}
+
+// Options that a group always runs with. Command line flags are added on
+// top of these, so they can enable more but can't turn these off.
+#[derive(Clone, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct DefaultOptions {
+    #[serde(default)]
+    release: bool,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    all_features: bool,
+    #[serde(default)]
+    no_default_features: bool,
+    #[serde(default)]
+    target: Option<String>,
+}
+
+impl DefaultOptions {
+    fn add_to_features(&self, features: &mut clap_cargo::Features) {
+        features.all_features |= self.all_features;
+        features.no_default_features |= self.no_default_features;
+        for feature in &self.features {
+            if !features.features.contains(feature) {
+                features.features.push(feature.clone());
+            }
+        }
+    }
 }
 
-trait Options {
+trait Options: Sync {
     fn add_to_command(&self, _cmd: &mut process::Command) {}
+
+    fn apply_defaults(&mut self, _defaults: &DefaultOptions) {}
+
+    // Narrows down the crates the command runs on
+    fn select_members<'a>(&self, members: Vec<&'a Package>) -> Result<Vec<&'a Package>> {
+        Ok(members)
+    }
+
+    // Whether `--lib` may be added automatically when every crate only has library targets
+    fn auto_lib(&self) -> bool {
+        false
+    }
+
+    // Whether each crate has to be run in its own cargo invocation
+    fn per_crate(&self) -> bool {
+        false
+    }
+
+    // Whether the cargo command accepts feature flags
+    fn takes_features(&self) -> bool {
+        true
+    }
+
+    // Whether a per-crate run has to go through the crates one at a time, in order
+    fn sequential(&self) -> bool {
+        false
+    }
+
+    // How long to wait between crates in a per-crate run
+    fn delay_between_crates(&self) -> Option<Duration> {
+        None
+    }
+
+    // Where to write a JUnit report of the per-crate results
+    fn junit_path(&self) -> Option<&Path> {
+        None
+    }
+
+    // Where cargo copies the final artifacts to
+    fn artifact_dir(&self) -> Option<&Path> {
+        None
+    }
 }
 
 // Common flags like --release
@@ -40,21 +188,201 @@ where
 {
     #[arg(long)]
     release: bool,
+    /// Build with the given cargo profile. A profile on the command line replaces
+    /// `release` in the group's defaults
+    #[arg(long, value_name = "PROFILE-NAME", conflicts_with = "release")]
+    profile: Option<String>,
+    /// Build for the target triple
+    #[arg(long, value_name = "TRIPLE")]
+    target: Option<String>,
+    /// Number of parallel jobs for cargo to run. This limits the parallelism of each
+    /// cargo invocation, not how many crates run at once
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+    /// Require Cargo.lock and the cache to be up to date, like --locked and --offline together
+    #[arg(long)]
+    frozen: bool,
+    /// Require Cargo.lock to be up to date
+    #[arg(long)]
+    locked: bool,
+    /// Run without accessing the network
+    #[arg(long)]
+    offline: bool,
+    /// Pass `--quiet` to cargo, hiding its progress lines like `Compiling` while still
+    /// showing errors
+    #[arg(long)]
+    quiet_cargo: bool,
+    /// Override the opt-level of the active profile (dev, release with --release, or the
+    /// one given with --profile)
+    #[arg(long, value_name = "LEVEL", value_parser = ["0", "1", "2", "3", "s", "z"])]
+    opt_level: Option<String>,
+    /// Override the debug setting of the active profile, e.g. `false` or `line-tables-only`
+    #[arg(long, value_name = "DEBUG")]
+    debug: Option<String>,
+    /// Compile with `-C target-cpu=native`. The resulting artifacts are optimized
+    /// for this machine and may not run on other CPUs
+    #[arg(long)]
+    native: bool,
+    /// Wrap compiler diagnostics at this many columns. Cargo has no flag for this,
+    /// so it's passed to rustc through RUSTFLAGS, which invalidates previous builds
+    #[arg(long, value_name = "COLUMNS")]
+    diagnostic_width: Option<u32>,
+    /// Copy the final artifacts of the group to this directory, which is created if
+    /// missing. Requires nightly
+    #[arg(long, value_name = "PATH")]
+    artifact_dir: Option<PathBuf>,
+    /// Print the planned compilation units as JSON instead of building. Requires nightly
+    #[arg(long)]
+    unit_graph: bool,
+    /// Enable the nightly `-Z target-applies-to-host` flag, so `[target]` config
+    /// isn't applied to build scripts and proc macros when cross-compiling
+    #[arg(long)]
+    target_applies_to_host: bool,
+    /// Pass `-Z unstable-options` to cargo. Like every unstable flag, this requires
+    /// a nightly toolchain, which is checked before running
+    #[arg(long)]
+    unstable_options: bool,
+    /// Unstable (nightly-only) flags to forward to cargo. Can be repeated
+    #[arg(short = 'Z', value_name = "FLAG")]
+    unstable_flags: Vec<String>,
     #[command(flatten)]
     specific: Specific,
 }
 
+impl<T> CommandOptions<T>
+where
+    T: Parser + ClapArgs,
+{
+    // --frozen, --locked and --offline, which cargo metadata takes too
+    fn lockfile_flags(&self) -> Vec<&'static str> {
+        let mut flags = Vec::new();
+        if self.frozen {
+            flags.push("--frozen");
+        }
+        if self.locked {
+            flags.push("--locked");
+        }
+        if self.offline {
+            flags.push("--offline");
+        }
+        flags
+    }
+}
+
 impl<T> Options for CommandOptions<T>
 where
     T: Options + Parser + ClapArgs,
 {
     fn add_to_command(&self, cmd: &mut process::Command) {
-        let Self { release, specific } = self;
+        let Self {
+            release,
+            profile,
+            target,
+            jobs,
+            frozen: _,
+            locked: _,
+            offline: _,
+            quiet_cargo,
+            opt_level,
+            debug,
+            native,
+            diagnostic_width,
+            artifact_dir,
+            unit_graph,
+            target_applies_to_host,
+            unstable_options,
+            unstable_flags,
+            specific,
+        } = self;
         if *release {
             cmd.arg("--release");
         }
+        if let Some(profile) = profile {
+            cmd.arg("--profile").arg(profile);
+        }
+        if let Some(target) = target {
+            cmd.arg("--target").arg(target);
+        }
+        if let Some(jobs) = jobs {
+            cmd.arg("--jobs").arg(jobs.to_string());
+        }
+        cmd.args(self.lockfile_flags());
+        if *quiet_cargo {
+            cmd.arg("--quiet");
+        }
+        let profile = match profile {
+            Some(profile) => profile.as_str(),
+            None if *release => "release",
+            None => "dev",
+        };
+        if let Some(opt_level) = opt_level {
+            cmd.arg("--config").arg(format!(
+                "profile.{}.opt-level={}",
+                profile,
+                toml_value(opt_level)
+            ));
+        }
+        if let Some(debug) = debug {
+            cmd.arg("--config")
+                .arg(format!("profile.{}.debug={}", profile, toml_value(debug)));
+        }
+        if *native {
+            add_rustflags(cmd, &["-C", "target-cpu=native"]);
+        }
+        if let Some(width) = diagnostic_width {
+            add_rustflags(cmd, &[&format!("--diagnostic-width={}", width)]);
+        }
+
+        // Unstable flags that our own options need, unless they were passed with -Z already
+        let mut implied_flags = Vec::new();
+        if *unit_graph {
+            cmd.arg("--unit-graph");
+        }
+        if *unit_graph || artifact_dir.is_some() || *unstable_options {
+            implied_flags.push("unstable-options");
+        }
+        if *target_applies_to_host {
+            implied_flags.push("target-applies-to-host");
+        }
+        for flag in implied_flags {
+            if !unstable_flags.iter().any(|existing| existing == flag) {
+                cmd.arg("-Z").arg(flag);
+            }
+        }
+        for flag in unstable_flags {
+            cmd.arg("-Z").arg(flag);
+        }
         specific.add_to_command(cmd);
     }
+
+    fn apply_defaults(&mut self, defaults: &DefaultOptions) {
+        // Cargo doesn't accept --release along with --profile
+        self.release |= defaults.release && self.profile.is_none();
+        if self.target.is_none() {
+            self.target = defaults.target.clone();
+        }
+        self.specific.apply_defaults(defaults);
+    }
+
+    fn select_members<'a>(&self, members: Vec<&'a Package>) -> Result<Vec<&'a Package>> {
+        self.specific.select_members(members)
+    }
+
+    fn auto_lib(&self) -> bool {
+        self.specific.auto_lib()
+    }
+
+    fn per_crate(&self) -> bool {
+        self.specific.per_crate()
+    }
+
+    fn junit_path(&self) -> Option<&Path> {
+        self.specific.junit_path()
+    }
+
+    fn artifact_dir(&self) -> Option<&Path> {
+        self.artifact_dir.as_deref()
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -62,6 +390,303 @@ struct DefaultSpecificOptions;
 
 impl Options for DefaultSpecificOptions {}
 
+// Test-specific flags like --isolate-features
+#[derive(Parser, Debug)]
+struct TestOptions {
+    /// Test each crate in its own cargo invocation, so features are unified per
+    /// crate like when it's built on its own. Slower, since shared dependencies
+    /// may be rebuilt for every crate
+    #[arg(long)]
+    isolate_features: bool,
+    /// Write a JUnit XML report with a test case for each crate to this path.
+    /// Requires --per-crate or --isolate-features
+    #[arg(long, value_name = "PATH")]
+    junit: Option<PathBuf>,
+    /// Run the tests in a random order, using SEED to reproduce an earlier order.
+    /// The test harness only supports this on nightly
+    #[arg(long, value_name = "SEED", num_args = 0..=1)]
+    shuffle: Option<Option<u64>>,
+    /// Only run unit and integration tests, skipping doctests
+    #[arg(long, conflicts_with = "doc_tests_only")]
+    lib_tests_only: bool,
+    /// Only run doctests. Crates without a library are skipped, since only libraries
+    /// have doctests
+    #[arg(long)]
+    doc_tests_only: bool,
+    /// Arguments passed to the test harness, e.g. `-- --nocapture`
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+impl TestOptions {
+    // Picks the seed once so every cargo invocation shuffles the same way
+    fn choose_shuffle_seed(&mut self) {
+        if let Some(seed) = &mut self.shuffle {
+            let seed = *seed.get_or_insert_with(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_nanos() as u64)
+                    .unwrap_or_default()
+            });
+            eprintln!("{}", format!("Shuffling tests with seed {}", seed).dimmed());
+        }
+    }
+}
+
+impl Options for TestOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if self.lib_tests_only {
+            cmd.arg("--tests");
+        }
+        if self.doc_tests_only {
+            cmd.arg("--doc");
+        }
+        // Everything after `--` goes to the test harness, so it comes last
+        let mut harness_args = Vec::new();
+        if let Some(Some(seed)) = self.shuffle {
+            harness_args.extend([
+                "-Z".to_string(),
+                "unstable-options".to_string(),
+                "--shuffle-seed".to_string(),
+                seed.to_string(),
+            ]);
+        }
+        harness_args.extend(self.args.iter().cloned());
+        if !harness_args.is_empty() {
+            cmd.arg("--").args(harness_args);
+        }
+    }
+
+    fn select_members<'a>(&self, members: Vec<&'a Package>) -> Result<Vec<&'a Package>> {
+        if !self.doc_tests_only {
+            return Ok(members);
+        }
+
+        // `cargo test --doc` fails for crates without a library, which are the
+        // only targets with doctests
+        let members: Vec<&Package> = members
+            .into_iter()
+            .filter(|package| package.targets.iter().any(|target| target.doctest))
+            .collect();
+        if members.is_empty() {
+            return Err(anyhow::anyhow!("No crate in the group has doctests"));
+        }
+
+        Ok(members)
+    }
+
+    fn per_crate(&self) -> bool {
+        self.isolate_features
+    }
+
+    fn junit_path(&self) -> Option<&Path> {
+        self.junit.as_deref()
+    }
+}
+
+// Build-specific flags like --build-plan
+#[derive(Parser, Debug)]
+struct BuildOptions {
+    /// Print the JSON build plan instead of building. Requires a nightly cargo that
+    /// still supports it
+    #[arg(long, conflicts_with = "unit_graph")]
+    build_plan: bool,
+}
+
+impl Options for BuildOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if self.build_plan {
+            cmd.arg("--build-plan").arg("-Z").arg("unstable-options");
+        }
+    }
+}
+
+// Check-specific flags like --lib
+#[derive(Parser, Debug)]
+struct CheckOptions {
+    /// Only check the library targets
+    #[arg(long)]
+    lib: bool,
+    /// Don't pass `--lib` automatically when the group only contains libraries
+    #[arg(long)]
+    no_auto_lib: bool,
+}
+
+impl Options for CheckOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if self.lib {
+            cmd.arg("--lib");
+        }
+    }
+
+    fn auto_lib(&self) -> bool {
+        !self.lib && !self.no_auto_lib
+    }
+}
+
+// Run-specific flags like --bin
+#[derive(Parser, Debug)]
+struct RunOptions {
+    /// Name of the binary to run. The crate in the group that defines it is run
+    #[arg(long)]
+    bin: Option<String>,
+    /// Arguments passed to the binary
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+impl Options for RunOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if let Some(bin) = &self.bin {
+            cmd.arg("--bin").arg(bin);
+        }
+        if !self.args.is_empty() {
+            cmd.arg("--").args(&self.args);
+        }
+    }
+
+    fn select_members<'a>(&self, members: Vec<&'a Package>) -> Result<Vec<&'a Package>> {
+        let Some(bin) = &self.bin else {
+            // Without --bin, every crate with a binary is run in turn, which only
+            // works if cargo knows which of its binaries to run
+            let members: Vec<&Package> = members
+                .into_iter()
+                .filter(|package| !binary_names(package).is_empty())
+                .collect();
+            if members.is_empty() {
+                return Err(anyhow::anyhow!("No crate in the group has a binary"));
+            }
+            for package in &members {
+                let binaries = binary_names(package);
+                if binaries.len() > 1 && package.default_run.is_none() {
+                    return Err(anyhow::anyhow!(
+                        "{} has several binaries ({}), pass --bin to pick the one to run",
+                        package.name,
+                        binaries.join(", ")
+                    ));
+                }
+            }
+            return Ok(members);
+        };
+
+        let owners: Vec<&Package> = members
+            .into_iter()
+            .filter(|package| {
+                package.targets.iter().any(|target| {
+                    &target.name == bin && target.kind.iter().any(|kind| kind == "bin")
+                })
+            })
+            .collect();
+
+        match owners.len() {
+            0 => Err(anyhow::anyhow!(
+                "No crate in the group has a binary named {}",
+                bin
+            )),
+            1 => Ok(owners),
+            _ => Err(anyhow::anyhow!(
+                "Several crates in the group have a binary named {}: {}",
+                bin,
+                owners
+                    .iter()
+                    .map(|package| package.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    // `cargo run` runs a single binary, so the crates run one after another
+    fn per_crate(&self) -> bool {
+        true
+    }
+}
+
+// Flags for `cargo fmt`, which doesn't take the common flags or features
+#[derive(Parser, Debug)]
+struct FmtOptions {
+    /// Check the formatting instead of changing files
+    #[arg(long)]
+    check: bool,
+}
+
+impl Options for FmtOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if self.check {
+            cmd.arg("--check");
+        }
+    }
+
+    fn takes_features(&self) -> bool {
+        false
+    }
+}
+
+// Flags for `cargo publish`, which doesn't take the common flags
+#[derive(Parser, Debug)]
+struct PublishOptions {
+    /// Don't build the crates before uploading them
+    #[arg(long)]
+    no_verify: bool,
+    /// Seconds to wait between publishing crates, to give the registry time to make a
+    /// crate available before its dependents are published
+    #[arg(long, value_name = "SECONDS")]
+    wait: Option<u64>,
+    // Set from --dry-run, which cargo publish handles itself
+    #[arg(skip)]
+    dry_run: bool,
+}
+
+impl Options for PublishOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if self.no_verify {
+            cmd.arg("--no-verify");
+        }
+        if self.dry_run {
+            cmd.arg("--dry-run");
+        }
+    }
+
+    fn per_crate(&self) -> bool {
+        true
+    }
+
+    // A crate can only be published after its dependencies
+    fn sequential(&self) -> bool {
+        true
+    }
+
+    fn delay_between_crates(&self) -> Option<Duration> {
+        // Nothing is uploaded in a dry run
+        match self.wait {
+            Some(seconds) if !self.dry_run => Some(Duration::from_secs(seconds)),
+            _ => None,
+        }
+    }
+}
+
+// Doc-specific flags like --open
+#[derive(Parser, Debug)]
+struct DocOptions {
+    /// Open the docs in a browser after building them
+    #[arg(long)]
+    open: bool,
+    /// Don't build documentation for dependencies
+    #[arg(long)]
+    no_deps: bool,
+}
+
+impl Options for DocOptions {
+    fn add_to_command(&self, cmd: &mut process::Command) {
+        if self.open {
+            cmd.arg("--open");
+        }
+        if self.no_deps {
+            cmd.arg("--no-deps");
+        }
+    }
+}
+
 // Clippy-specific flags like --fix
 #[derive(Parser, Debug)]
 struct ClippyOptions {
@@ -69,7 +694,8 @@ struct ClippyOptions {
     fix: bool,
     #[arg(long)]
     allow_dirty: bool,
-    #[arg(trailing_var_arg = true)]
+    /// Arguments passed to clippy, e.g. `-- -D warnings`
+    #[arg(last = true)]
     pass_through_args: Vec<String>,
 }
 
@@ -107,53 +733,482 @@ struct Args {
     _subcommand_name: String,
     #[arg(long)]
     cwd: Option<PathBuf>,
+    /// Format of the log output enabled with RUST_LOG
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// When to color the output, of both cargo-groups and cargo. `auto` colors output to a
+    /// terminal, unless NO_COLOR is set
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Resolve symlinks in crate directories before matching `path:` patterns.
+    /// By default patterns match the paths reported by cargo, which keep symlinks as is
+    #[arg(long)]
+    dereference_symlinks: bool,
+    /// Don't let `*` in path patterns match `/`, so `crates/*` only matches the direct
+    /// children of `crates` and `crates/**` is needed to match nested crates
+    #[arg(long)]
+    workspace_relative_globs: bool,
+    /// Run the command in every workspace whose root manifest matches this glob, relative to
+    /// the current directory, e.g. `services/*`. Each workspace uses its own groups
+    // clap_cargo names the --manifest-path argument PATH
+    #[arg(long, value_name = "PATTERN", conflicts_with = "PATH")]
+    workspaces_glob: Option<String>,
+    /// Path of the generated groups file. Defaults to groups.generated.toml next to
+    /// the root Cargo.toml, if it exists
+    #[arg(long, value_name = "PATH")]
+    generated_groups: Option<PathBuf>,
+    /// Exit successfully instead of failing when the workspace doesn't define any groups
+    #[arg(long)]
+    allow_no_groups: bool,
+    /// Match the group patterns again instead of reusing the crates they matched in an
+    /// earlier run
+    #[arg(long)]
+    no_cache: bool,
     #[command(flatten)]
     manifest: clap_cargo::Manifest,
+    #[command(flatten)]
+    execution: ExecutionOptions,
+    /// Print the JSON schema of the cargo-groups configuration in Cargo.toml
+    #[arg(long)]
+    json_schema: bool,
+    /// List the built-in commands and the custom commands of the workspace, and whether the
+    /// cargo commands that custom commands run are installed
+    #[arg(long)]
+    list_commands: bool,
     #[command(subcommand)]
-    command: Command,
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+// Flags that control how cargo-groups runs cargo, as opposed to
+// flags that are forwarded to cargo itself
+#[derive(ClapArgs, Clone, Debug, Default)]
+struct ExecutionOptions {
+    /// Run cargo separately for each crate in the group instead of once for the whole group
+    #[arg(long, global = true, alias = "per-package")]
+    per_crate: bool,
+    /// Resume a per-crate run, skipping the crates before this one
+    #[arg(long, global = true, value_name = "CRATE")]
+    from: Option<String>,
+    /// Hide cargo's output for crates that pass and print a summary at the end
+    #[arg(long, global = true)]
+    summary_only: bool,
+    /// Print how many crates the command ran on, how long it took and whether it passed.
+    /// In per-crate mode, also print the result of each crate
+    #[arg(long, global = true)]
+    summary: bool,
+    /// Keep running the remaining crates after a crate fails, and print a summary at the end
+    #[arg(long, global = true)]
+    continue_on_error: bool,
+    /// Kill the command for a crate if it runs for longer than this many seconds
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Run cargo for up to this many crates at once
+    #[arg(long, global = true, value_name = "N")]
+    group_jobs: Option<usize>,
+    /// Share a single jobserver between the cargo processes run with --group-jobs,
+    /// so together they don't run more compiler jobs than there are CPUs
+    #[arg(long, global = true, requires = "group_jobs")]
+    jobserver: bool,
+    /// Read features separated by whitespace or commas from stdin and add them
+    /// to the features passed with --features
+    #[arg(long, global = true)]
+    stdin_features: bool,
+    /// Skip crates whose sources and workspace dependencies haven't changed
+    /// since the last successful run of the same command
+    #[arg(long, global = true)]
+    skip_unchanged: bool,
+    /// Shell command to run in the workspace root before the cargo command.
+    /// The cargo command is skipped if it fails
+    #[arg(long, global = true, value_name = "COMMAND")]
+    pre_command: Option<String>,
+    /// Shell command to run in the workspace root after the cargo command
+    #[arg(long, global = true, value_name = "COMMAND")]
+    post_command: Option<String>,
+    /// Refuse to run if the git worktree has uncommitted changes. Useful with
+    /// commands that modify files, like `clippy --fix`
+    #[arg(long, global = true)]
+    require_clean_worktree: bool,
+    /// Print the cargo commands that would run as JSON lines, with their program,
+    /// arguments, working directory and environment overrides, instead of running them
+    #[arg(long, global = true)]
+    print_cmd_json: bool,
+    /// Print the cargo commands that would run, ready to copy into a shell, instead of
+    /// running them
+    #[arg(long, global = true, conflicts_with = "print_cmd_json")]
+    dry_run: bool,
+    /// Print the targets of each selected crate, grouped by kind, instead of running cargo
+    #[arg(long, global = true)]
+    print_targets: bool,
+    /// Fail instead of warning when a group pattern matches no crates
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Explain how the crates and features were selected before running, including what each
+    /// pattern matched and which crates were removed and why
+    #[arg(long, global = true)]
+    explain_selection: bool,
+    /// Apply the options of a profile from `[workspace.metadata.groups-profiles]`
+    #[arg(long, global = true, value_name = "NAME")]
+    profile_name: Option<String>,
+    /// Also write the output of the run to this file, including the output of cargo
+    #[arg(long, global = true, value_name = "PATH")]
+    report_file: Option<PathBuf>,
+    /// Show the crates the command will run on and ask before running it.
+    /// There's no prompt when stdin isn't a terminal
+    #[arg(long, global = true)]
+    confirm: bool,
+    /// Answer yes to the --confirm prompt
+    #[arg(long, global = true, requires = "confirm")]
+    yes: bool,
+    /// Save the number of warnings of each crate as the group's baseline
+    #[arg(long, global = true, conflicts_with_all = ["compare_baseline", "per_crate"])]
+    save_baseline: bool,
+    /// Fail if any crate has more warnings than in the group's saved baseline
+    #[arg(long, global = true, conflicts_with = "per_crate")]
+    compare_baseline: bool,
+    /// File to store warning baselines in. Defaults to a file in the target directory
+    #[arg(long, global = true, value_name = "PATH")]
+    baseline_file: Option<PathBuf>,
+    /// Fail if the group has more than this many warnings in total
+    #[arg(long, global = true, value_name = "N", conflicts_with = "per_crate")]
+    fail_on_warnings_count: Option<usize>,
+}
+
+impl ExecutionOptions {
+    // Whether commands are only printed, not run
+    fn prints_commands(&self) -> bool {
+        self.print_cmd_json || self.dry_run
+    }
+
+    // Whether the warnings in cargo's output need to be counted
+    fn counts_warnings(&self) -> bool {
+        self.warnings_flag().is_some()
+    }
+
+    // The first flag passed that counts the warnings of the whole group
+    fn warnings_flag(&self) -> Option<&'static str> {
+        [
+            (self.save_baseline, "--save-baseline"),
+            (self.compare_baseline, "--compare-baseline"),
+            (
+                self.fail_on_warnings_count.is_some(),
+                "--fail-on-warnings-count",
+            ),
+        ]
+        .into_iter()
+        .find_map(|(passed, flag)| passed.then_some(flag))
+    }
+
+    // The first flag passed that only applies when cargo runs once per crate
+    fn per_crate_flag(&self) -> Option<&'static str> {
+        [
+            (self.from.is_some(), "--from"),
+            (self.summary_only, "--summary-only"),
+            (self.continue_on_error, "--continue-on-error"),
+            (self.timeout.is_some(), "--timeout"),
+            (self.group_jobs.is_some(), "--group-jobs"),
+        ]
+        .into_iter()
+        .find_map(|(passed, flag)| passed.then_some(flag))
+    }
+}
+
+// Which crates a command runs on
+#[derive(ClapArgs, Debug)]
+struct Selection {
+    /// The groups to run on. Crates in several of them only run once.
+    /// Defaults to the group named `default`
+    #[arg(value_name = "GROUP")]
+    groups: Vec<String>,
+    /// Run on the crates of every group in the workspace
+    #[arg(long, conflicts_with = "groups")]
+    all_groups: bool,
+    /// Remove the crates of another group from the selection. Can be repeated
+    #[arg(long, value_name = "GROUP")]
+    exclude_group: Vec<String>,
+    /// Remove the crates whose name matches this glob from the selection. Can be repeated
+    #[arg(long, value_name = "CRATE")]
+    exclude: Vec<String>,
+    /// Only run on one of several shards of the group, e.g. `1/3` for the first of three.
+    /// Crates are sorted by name and dealt out to the shards in turn
+    #[arg(long, value_name = "INDEX/TOTAL")]
+    partition: Option<Partition>,
+    /// Only run on the crates with no changes since this git ref, including
+    /// uncommitted and untracked files
+    #[arg(long, value_name = "REF")]
+    exclude_changed: Option<String>,
+    /// How to skip crates that are dependencies of other crates in the selection.
+    /// Build, check and clippy default to resolve-graph, test and run to none
+    #[arg(long, value_enum, value_name = "STRATEGY")]
+    dedup_strategy: Option<DedupStrategy>,
+}
+
+impl Selection {
+    // The selected groups, for messages
+    fn name(&self) -> String {
+        if self.all_groups {
+            "all groups".to_string()
+        } else if self.groups.is_empty() {
+            DEFAULT_GROUP.to_string()
+        } else {
+            self.groups.join(", ")
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DedupStrategy {
+    /// Run on every selected crate
+    None,
+    /// Skip crates whose name is a declared dependency of another selected crate
+    DeclaredNames,
+    /// Skip crates that another selected crate depends on in the resolved dependency graph
+    ResolveGraph,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Partition {
+    // 1-based
+    index: usize,
+    total: usize,
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (index, total) = s
+            .split_once('/')
+            .ok_or_else(|| "expected <INDEX>/<TOTAL>, e.g. 1/3".to_string())?;
+        let index: usize = index
+            .parse()
+            .map_err(|_| format!("invalid partition index {}", index))?;
+        let total: usize = total
+            .parse()
+            .map_err(|_| format!("invalid partition total {}", total))?;
+        if total == 0 || index == 0 || index > total {
+            return Err(format!("partition index must be between 1 and {}", total));
+        }
+
+        Ok(Self { index, total })
+    }
+}
+
+impl Partition {
+    fn select<'a>(&self, mut packages: Vec<&'a Package>) -> Vec<&'a Package> {
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        packages
+            .into_iter()
+            .enumerate()
+            .filter(|(position, _)| position % self.total == self.index - 1)
+            .map(|(_, package)| package)
+            .collect()
+    }
 }
 
 #[derive(Parser, Debug)]
 enum Command {
     /// Test a group of crates
-    #[command(override_usage = "Usage: cargo groups test [OPTIONS] <GROUP>")]
+    #[command(override_usage = "Usage: cargo groups test [OPTIONS] [GROUP]... [-- <ARGS>...]")]
     Test {
-        group: String,
+        #[command(flatten)]
+        selection: Selection,
         #[command(flatten)]
         features: clap_cargo::Features,
         #[command(flatten)]
-        options: CommandOptions,
+        options: CommandOptions<TestOptions>,
     },
     /// Build a group of crates
-    #[command(override_usage = "Usage: cargo groups build [OPTIONS] <GROUP>")]
+    #[command(override_usage = "Usage: cargo groups build [OPTIONS] [GROUP]...")]
     Build {
-        group: String,
+        #[command(flatten)]
+        selection: Selection,
         #[command(flatten)]
         features: clap_cargo::Features,
         #[command(flatten)]
-        options: CommandOptions,
+        options: CommandOptions<BuildOptions>,
     },
     /// Check a group of crates
-    #[command(override_usage = "Usage: cargo groups check [OPTIONS] <GROUP>")]
+    #[command(override_usage = "Usage: cargo groups check [OPTIONS] [GROUP]...")]
     Check {
-        group: String,
+        #[command(flatten)]
+        selection: Selection,
         #[command(flatten)]
         features: clap_cargo::Features,
         #[command(flatten)]
-        options: CommandOptions,
+        options: CommandOptions<CheckOptions>,
     },
     /// Run clippy on a group of crates
-    #[command(override_usage = "Usage: cargo groups clippy [OPTIONS] <GROUP>")]
+    #[command(override_usage = "Usage: cargo groups clippy [OPTIONS] [GROUP]... [-- <ARGS>...]")]
     Clippy {
-        group: String,
+        #[command(flatten)]
+        selection: Selection,
         #[command(flatten)]
         features: clap_cargo::Features,
         #[command(flatten)]
         options: CommandOptions<ClippyOptions>,
     },
-    /// List the groups in the workspace. Add a group name to list the crates in that specific group
+    /// Format a group of crates
+    #[command(override_usage = "Usage: cargo groups fmt [OPTIONS] [GROUP]...")]
+    Fmt {
+        #[command(flatten)]
+        selection: Selection,
+        #[command(flatten)]
+        options: FmtOptions,
+    },
+    /// Build the documentation of a group of crates
+    #[command(override_usage = "Usage: cargo groups doc [OPTIONS] [GROUP]...")]
+    Doc {
+        #[command(flatten)]
+        selection: Selection,
+        #[command(flatten)]
+        features: clap_cargo::Features,
+        #[command(flatten)]
+        options: CommandOptions<DocOptions>,
+    },
+    /// Publish a group of crates, one at a time in dependency order
+    #[command(override_usage = "Usage: cargo groups publish [OPTIONS] [GROUP]...")]
+    Publish {
+        #[command(flatten)]
+        selection: Selection,
+        #[command(flatten)]
+        features: clap_cargo::Features,
+        #[command(flatten)]
+        options: PublishOptions,
+    },
+    /// Run a binary from a group of crates
+    #[command(override_usage = "Usage: cargo groups run [OPTIONS] [GROUP]... [-- <ARGS>...]")]
+    Run {
+        #[command(flatten)]
+        selection: Selection,
+        #[command(flatten)]
+        features: clap_cargo::Features,
+        #[command(flatten)]
+        options: CommandOptions<RunOptions>,
+    },
+    /// List the groups in the workspace. Add a group name to list the crates in that specific group,
+    /// or a glob like `svc-*` to list the groups whose name matches it
     #[command(override_usage = "Usage: cargo groups list [GROUP]")]
-    List { group: Option<String> },
+    List {
+        group: Option<String>,
+        /// Line to print between groups, e.g. an empty string for a blank line
+        #[arg(long, value_name = "SEPARATOR", allow_hyphen_values = true)]
+        group_separator: Option<String>,
+        /// Print the groups and their crates as JSON
+        #[arg(long, conflicts_with = "group_separator")]
+        json: bool,
+        /// Only print a `name: description` line for each group, e.g. to generate docs
+        #[arg(long, conflicts_with_all = ["group_separator", "json"])]
+        descriptions_only: bool,
+    },
+    /// Print the environment variables a build of the group would set, without running it
+    #[command(override_usage = "Usage: cargo groups env [OPTIONS] [GROUP]...")]
+    Env {
+        #[command(flatten)]
+        selection: Selection,
+        #[command(flatten)]
+        features: clap_cargo::Features,
+        #[command(flatten)]
+        options: CommandOptions,
+    },
+    /// Print the absolute manifest path of each crate in a group, one per line
+    #[command(override_usage = "Usage: cargo groups members [OPTIONS] <GROUP>")]
+    Members {
+        group: String,
+        /// Separate paths with a NUL character instead of a newline
+        #[arg(long)]
+        print0: bool,
+    },
+    /// Show which settings and dependencies the crates in a group inherit from the workspace
+    #[command(override_usage = "Usage: cargo groups inheritance <GROUP>")]
+    Inheritance { group: String },
+    /// Print how the crates in a group depend on each other, as a tree
+    ///
+    /// Only dependencies between crates of the group are shown, starting from the crates
+    /// that no other crate in the group depends on. Dev-dependencies are left out
+    #[command(override_usage = "Usage: cargo groups tree <GROUP>")]
+    Tree { group: String },
+    /// Compare the external dependencies of two groups
+    #[command(override_usage = "Usage: cargo groups diff-deps <FIRST> <SECOND>")]
+    DiffDeps { first: String, second: String },
+    /// Check that every crate in the workspace belongs to at least one group
+    #[command(override_usage = "Usage: cargo groups verify-members")]
+    VerifyMembers,
+    /// List the crates that don't belong to any group
+    #[command(override_usage = "Usage: cargo groups orphans [--json]")]
+    Orphans {
+        /// Print the crates as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show which crate and groups a file belongs to
+    #[command(override_usage = "Usage: cargo groups touched <PATH>")]
+    Touched { path: PathBuf },
+    /// Rename a group, preserving its patterns and the formatting of Cargo.toml
+    #[command(override_usage = "Usage: cargo groups rename <OLD> <NEW>")]
+    Rename { old: String, new: String },
+    /// Run any cargo command on a group of crates
+    ///
+    /// The arguments after the group are passed to cargo untouched, followed by a `-p` flag
+    /// for each crate
+    #[command(override_usage = "Usage: cargo groups exec <GROUP> <ARGS>...")]
+    Exec {
+        group: String,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Print a completion script for a shell
+    ///
+    /// The script completes the `cargo-groups` binary, since shells complete `cargo` itself
+    #[command(override_usage = "Usage: cargo groups completions <SHELL>")]
+    Completions { shell: clap_complete::Shell },
+    /// Run a command on a group again whenever a file in one of its crates changes
+    ///
+    /// The command is any cargo-groups command, which runs on the group,
+    /// e.g. `cargo groups watch core check`. Build output in `target` is ignored
+    #[command(override_usage = "Usage: cargo groups watch [OPTIONS] <GROUP> <COMMAND>...")]
+    Watch {
+        group: String,
+        /// How long the files have to stay unchanged before the command runs again
+        #[arg(long, value_name = "MS", default_value_t = 500)]
+        debounce: u64,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Run the last test, build, check, clippy, run, exec or custom command again
+    #[command(override_usage = "Usage: cargo groups repeat")]
+    Repeat,
+    // Custom commands defined in `[workspace.metadata.groups-commands]`
+    #[command(external_subcommand)]
+    Custom(Vec<String>),
+}
+
+impl Command {
+    // The flags for cargo metadata, which can touch the network and Cargo.lock too
+    fn metadata_flags(&self) -> Vec<&'static str> {
+        match self {
+            Command::Test { options, .. } => options.lockfile_flags(),
+            Command::Build { options, .. } => options.lockfile_flags(),
+            Command::Check { options, .. } => options.lockfile_flags(),
+            Command::Clippy { options, .. } => options.lockfile_flags(),
+            Command::Doc { options, .. } => options.lockfile_flags(),
+            Command::Run { options, .. } => options.lockfile_flags(),
+            Command::Env { options, .. } => options.lockfile_flags(),
+            _ => Vec::new(),
+        }
+    }
 }
 
 impl RootCargoToml {
@@ -167,12 +1222,181 @@ impl RootCargoToml {
             .ok_or(anyhow::anyhow!("Cargo.toml not found"))
     }
 
-    fn load(manifest_path: &Path) -> Result<Self> {
-        let cargo_toml_contents = fs::read_to_string(&manifest_path)?;
-        Ok(toml::from_str::<RootCargoToml>(&cargo_toml_contents)?)
+    // Loads the configuration in Cargo.toml, along with the generated groups file if
+    // there is one. A missing file is only an error if its path was passed explicitly
+    fn load(manifest_path: &Path, generated_groups_path: Option<&Path>) -> Result<Self> {
+        let cargo_toml_contents = fs::read_to_string(manifest_path)?;
+        // Parse errors include duplicate keys, like a group defined twice
+        let mut cargo_toml =
+            toml::from_str::<RootCargoToml>(&cargo_toml_contents).map_err(|err| {
+                anyhow::anyhow!("Failed to parse {}: {}", manifest_path.display(), err)
+            })?;
+
+        // Merge the root package's groups, with the workspace's groups taking precedence
+        if let Some(package) = cargo_toml.package.take() {
+            let metadata = &mut cargo_toml.workspace.metadata;
+            for (group, patterns) in package.metadata.groups {
+                metadata.groups.entry(group).or_insert(patterns);
+            }
+            for (group, defaults) in package.metadata.groups_defaults {
+                metadata.groups_defaults.entry(group).or_insert(defaults);
+            }
+            for (name, template) in package.metadata.groups_commands {
+                metadata.groups_commands.entry(name).or_insert(template);
+            }
+            for (name, profile) in package.metadata.groups_profiles {
+                metadata.groups_profiles.entry(name).or_insert(profile);
+            }
+        }
+
+        // The configuration file takes precedence over Cargo.toml
+        let config_paths: Vec<PathBuf> = CONFIG_FILES
+            .iter()
+            .map(|name| manifest_path.with_file_name(name))
+            .filter(|path| path.exists())
+            .collect();
+        if config_paths.len() > 1 {
+            return Err(anyhow::anyhow!(
+                "Found both {} and {}, only one of them can be used",
+                CONFIG_FILES[0],
+                CONFIG_FILES[1]
+            ));
+        }
+        if let Some(config_path) = config_paths.first() {
+            let contents = fs::read_to_string(config_path)?;
+            let config = toml::from_str::<Metadata>(&contents).map_err(|err| {
+                anyhow::anyhow!("Failed to parse {}: {}", config_path.display(), err)
+            })?;
+            let metadata = &mut cargo_toml.workspace.metadata;
+            metadata.groups.extend(config.groups);
+            metadata.groups_defaults.extend(config.groups_defaults);
+            metadata.groups_commands.extend(config.groups_commands);
+            metadata.groups_profiles.extend(config.groups_profiles);
+        }
+
+        // Generated groups take precedence over the ones in Cargo.toml
+        let default_generated_groups_path = manifest_path.with_file_name(GENERATED_GROUPS_FILE);
+        let (generated_groups_path, generated_groups_contents) = match generated_groups_path {
+            Some(path) => (
+                path,
+                Some(fs::read_to_string(path).map_err(|err| {
+                    anyhow::anyhow!("Failed to read {}: {}", path.display(), err)
+                })?),
+            ),
+            None => (
+                default_generated_groups_path.as_path(),
+                fs::read_to_string(&default_generated_groups_path).ok(),
+            ),
+        };
+        if let Some(contents) = generated_groups_contents {
+            let generated = toml::from_str::<GeneratedGroups>(&contents).map_err(|err| {
+                anyhow::anyhow!(
+                    "Failed to parse {}: {}",
+                    generated_groups_path.display(),
+                    err
+                )
+            })?;
+            cargo_toml
+                .workspace
+                .metadata
+                .groups
+                .extend(generated.groups);
+        }
+
+        cargo_toml.validate_groups()?;
+
+        Ok(cargo_toml)
+    }
+
+    // Rejects defaults for groups that don't exist, empty groups and patterns that aren't
+    // valid globs, so the error names the group and pattern instead of coming up when the
+    // group is run
+    fn validate_groups(&self) -> Result<()> {
+        let groups = &self.workspace.metadata.groups;
+        let mut defaults: Vec<&String> = self.workspace.metadata.groups_defaults.keys().collect();
+        defaults.sort();
+        if let Some(name) = defaults.iter().find(|name| !groups.contains_key(**name)) {
+            return Err(anyhow::anyhow!(
+                "groups-defaults has options for group {}, which isn't defined",
+                name
+            ));
+        }
+
+        let mut names: Vec<&String> = groups.keys().collect();
+        names.sort();
+        for name in names {
+            let patterns = groups[name].patterns();
+            if patterns.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Group {} is empty, add patterns to it or remove it",
+                    name
+                ));
+            }
+            for pattern in patterns {
+                // Patterns using unset environment variables are reported when the group is run
+                let Ok(interpolated) = interpolate_env(pattern) else {
+                    continue;
+                };
+                if interpolated.starts_with("!group:") {
+                    return Err(excluded_group_error(name, pattern));
+                }
+                let glob = interpolated.strip_prefix('!').unwrap_or(&interpolated);
+                let result = if glob.starts_with("group:") {
+                    continue;
+                } else if let Some(package_glob) = ["pkg:", "deps:", "rdeps:"]
+                    .iter()
+                    .find_map(|prefix| glob.strip_prefix(prefix))
+                {
+                    Glob::new(package_glob)
+                        .map(|_| ())
+                        .map_err(anyhow::Error::from)
+                } else {
+                    let path = glob.strip_prefix("path:").unwrap_or(glob);
+                    path_glob(path, false).map(|_| ())
+                };
+                result.map_err(|err| {
+                    anyhow::anyhow!(
+                        "Group {} has an invalid pattern '{}': {}",
+                        name,
+                        pattern,
+                        err
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
     }
 }
 
+// How a single group pattern matches packages
+enum PatternMatcher<'a> {
+    // `pkg:` patterns
+    Name(GlobMatcher),
+    // `path:` and bare patterns
+    Path(GlobMatcher),
+    // `deps:` and `rdeps:` patterns, which match a set of packages computed upfront
+    Ids(HashSet<&'a PackageId>),
+    // `members` and `workspace:*`, which match every workspace member
+    All,
+}
+
+// The group that commands run on when no group is given
+const DEFAULT_GROUP: &str = "default";
+
+// Files next to the root Cargo.toml that can hold the configuration instead of
+// `[workspace.metadata]`, with the same tables
+const CONFIG_FILES: [&str; 2] = [".cargo-groups.toml", "cargo-groups.toml"];
+
+// Groups written by a build step, for workspaces whose crate lists are computed
+const GENERATED_GROUPS_FILE: &str = "groups.generated.toml";
+
+#[derive(Deserialize, Debug)]
+struct GeneratedGroups {
+    #[serde(default)]
+    groups: HashMap<String, GroupDefinition>,
+}
+
 fn add_features(cmd: &mut process::Command, features: &clap_cargo::Features) {
     if features.no_default_features {
         cmd.arg("--no-default-features");
@@ -182,222 +1406,5574 @@ fn add_features(cmd: &mut process::Command, features: &clap_cargo::Features) {
         cmd.arg("--all-features");
     }
 
+    // Bare arguments after `--features` aren't reliably read as features,
+    // so they're passed as a single comma separated value
     if !features.features.is_empty() {
-        cmd.arg("--features");
+        cmd.arg("--features").arg(features.features.join(","));
     }
+}
 
-    for feature in &features.features {
-        cmd.arg(feature);
+// Formats a command line value for a `--config` override, quoting it unless
+// it's a number or a boolean
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value == "true" || value == "false" {
+        value.to_string()
+    } else {
+        format!("{:?}", value)
     }
 }
 
-fn make_glob_set(globs: Vec<Glob>) -> Result<GlobSet> {
-    let mut glob_set_builder = GlobSetBuilder::new();
-    for glob in globs {
-        glob_set_builder.add(glob);
+// Adds rustflags without replacing the ones set in the environment. Cargo uses the
+// first of CARGO_ENCODED_RUSTFLAGS, RUSTFLAGS, `target.<triple>.rustflags` (joined with
+// `target.<cfg>.rustflags`) and `build.rustflags` that is set, so the flags go in the
+// environment, where no config can override them. We extend CARGO_ENCODED_RUSTFLAGS if
+// it's set, like cargo prefers it, and RUSTFLAGS otherwise. Rustflags from cargo's
+// config don't apply to the command
+fn add_rustflags(cmd: &mut process::Command, flags: &[&str]) {
+    // Flags added earlier are already set on the command
+    let current = |key: &str| match cmd.get_envs().find(|(name, _)| *name == key) {
+        Some((_, value)) => value.map(|value| value.to_string_lossy().into_owned()),
+        None => env::var(key).ok(),
+    };
+    if let Some(encoded) = current("CARGO_ENCODED_RUSTFLAGS") {
+        let mut all_flags: Vec<&str> = encoded.split('\x1f').filter(|f| !f.is_empty()).collect();
+        all_flags.extend(flags);
+        let all_flags = all_flags.join("\x1f");
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", all_flags);
+    } else {
+        let rustflags = current("RUSTFLAGS").unwrap_or_default();
+        let mut all_flags: Vec<&str> = rustflags.split_whitespace().collect();
+        all_flags.extend(flags);
+        let all_flags = all_flags.join(" ");
+        cmd.env("RUSTFLAGS", all_flags);
     }
+}
 
-    Ok(glob_set_builder.build()?)
+// Groups can't be excluded, since their own exclusions can't be negated pattern by pattern
+fn excluded_group_error(group: &str, pattern: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Group {} has the pattern '{}', but groups can't be excluded with `!`. Exclude the \
+         patterns of their crates instead, or pass --exclude-group",
+        group,
+        pattern
+    )
 }
 
-struct WorkspaceInfo {
-    cwd: PathBuf,
-    metadata: cargo_metadata::Metadata,
-    cargo_toml: RootCargoToml,
+// Renames a group in a table holding the configuration, along with its defaults.
+// Returns whether anything was renamed
+fn rename_group_in_metadata(metadata: &mut dyn toml_edit::TableLike, old: &str, new: &str) -> bool {
+    let mut renamed = false;
+    if let Some(groups) = metadata
+        .get_mut("groups")
+        .and_then(|groups| groups.as_table_like_mut())
+    {
+        renamed |= rename_group_in_table(groups, old, new);
+    }
+    if let Some(defaults) = metadata
+        .get_mut("groups-defaults")
+        .and_then(|defaults| defaults.as_table_like_mut())
+    {
+        renamed |= rename_key(defaults, old, new);
+    }
+    renamed
 }
 
-impl WorkspaceInfo {
-    fn from_args(args: &Args) -> Result<Self> {
-        let cwd = args.cwd.clone().unwrap_or_else(|| current_dir().unwrap());
-        let cargo_toml_path = RootCargoToml::find(&cwd, &args.manifest.manifest_path)?;
-        let metadata = MetadataCommand::new()
-            .manifest_path(&cargo_toml_path)
-            .exec()?;
-        let cargo_toml = RootCargoToml::load(&cargo_toml_path)?;
+// Renames a group in a table of groups, along with the `group:` references to it.
+// Returns whether anything was renamed
+fn rename_group_in_table(groups: &mut dyn toml_edit::TableLike, old: &str, new: &str) -> bool {
+    let mut renamed = rename_key(groups, old, new);
+    // Keep references from other groups pointing at the renamed group
+    for (_, definition) in groups.iter_mut() {
+        let patterns = match definition.as_table_like_mut() {
+            Some(table) => table
+                .get_mut("patterns")
+                .and_then(|item| item.as_array_mut()),
+            None => definition.as_array_mut(),
+        };
+        let Some(patterns) = patterns else {
+            continue;
+        };
+        for pattern in patterns.iter_mut() {
+            if pattern.as_str() == Some(&format!("group:{}", old)) {
+                let decor = pattern.decor().clone();
+                *pattern = toml_edit::Value::from(format!("group:{}", new));
+                *pattern.decor_mut() = decor;
+                renamed = true;
+            }
+        }
+    }
+    renamed
+}
 
-        Ok(Self {
-            cwd,
-            metadata,
-            cargo_toml,
-        })
+// Lists the fields of a crate manifest that are inherited from the
+// workspace with `workspace = true`, like `package.version` or `dependencies.serde`
+fn get_inherited_fields(manifest: &toml::Table) -> Vec<String> {
+    let is_inherited = |value: &toml::Value| {
+        value
+            .get("workspace")
+            .and_then(toml::Value::as_bool)
+            .unwrap_or(false)
+    };
+    let mut inherited = Vec::new();
+
+    for table in ["package", "lints"] {
+        match manifest.get(table) {
+            Some(value) if is_inherited(value) => inherited.push(table.to_string()),
+            Some(toml::Value::Table(fields)) => {
+                for (field, value) in fields {
+                    if is_inherited(value) {
+                        inherited.push(format!("{}.{}", table, field));
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 
-    fn print_groups(&self) -> Result<()> {
-        if self.cargo_toml.workspace.metadata.groups.is_empty() {
-            println!("No groups found");
-            return Ok(());
+    let mut dependency_tables = Vec::new();
+    for table in ["dependencies", "dev-dependencies", "build-dependencies"] {
+        dependency_tables.push((table.to_string(), manifest.get(table)));
+    }
+    if let Some(toml::Value::Table(targets)) = manifest.get("target") {
+        for (target, target_table) in targets {
+            for table in ["dependencies", "dev-dependencies", "build-dependencies"] {
+                dependency_tables.push((
+                    format!("target.{}.{}", target, table),
+                    target_table.get(table),
+                ));
+            }
+        }
+    }
+
+    for (table, dependencies) in dependency_tables {
+        let Some(toml::Value::Table(dependencies)) = dependencies else {
+            continue;
+        };
+        for (name, dependency) in dependencies {
+            if is_inherited(dependency) {
+                inherited.push(format!("{}.{}", table, name));
+            }
         }
+    }
+
+    inherited
+}
 
-        for (group, crates) in &self.cargo_toml.workspace.metadata.groups {
-            println!("[{}]", group);
-            for package in self.get_group_crates(&crates, false)? {
-                self.print_package(package);
+// Expands a custom command template like `udeps --all-targets {packages}` into
+// cargo arguments. `{packages}` becomes the `-p` flags for the group's crates
+// (appended at the end if the template doesn't use it), and `{group}` is
+// replaced by the group name. Arguments are split on whitespace.
+fn expand_command_template(
+    template: &str,
+    group: &str,
+    members: &[&Package],
+) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut has_packages = false;
+    for arg in template.split_whitespace() {
+        if arg == "{packages}" {
+            has_packages = true;
+            for member in members {
+                args.push("-p".to_string());
+                args.push(member.name.clone());
             }
+            continue;
         }
 
-        Ok(())
+        let arg = arg.replace("{group}", group);
+        if let Some(start) = arg.find('{') {
+            let placeholder = arg[start..]
+                .split_inclusive('}')
+                .next()
+                .unwrap_or(&arg[start..]);
+            return Err(anyhow::anyhow!(
+                "Unknown placeholder {} in command template `{}`. Expected {{packages}} or {{group}}",
+                placeholder,
+                template
+            ));
+        }
+        args.push(arg);
     }
 
-    fn print_package(&self, package: &Package) {
-        println!(
-            "  {} {}",
-            package.name,
-            self.get_package_path_relative_to_workspace(&package)
-                .display()
-                .to_string()
-                .dimmed()
-        );
+    if !has_packages {
+        for member in members {
+            args.push("-p".to_string());
+            args.push(member.name.clone());
+        }
     }
 
-    fn print_group(&self, group: &str) -> Result<()> {
-        let crates = self
-            .cargo_toml
-            .workspace
-            .metadata
-            .groups
-            .get(group)
-            .ok_or(anyhow::anyhow!("Group {} not found", group))?;
+    Ok(args)
+}
 
-        println!("[{}]", group);
-        for package in self.get_group_crates(crates, false)? {
-            self.print_package(package);
+// Renames a key in place. Returns whether the table had it
+fn rename_key(table: &mut dyn toml_edit::TableLike, old: &str, new: &str) -> bool {
+    if !table.contains_key(old) {
+        return false;
+    }
+
+    // Removing and re-inserting a key moves it to the end of the table,
+    // so we rebuild the whole table to keep the keys in their original order.
+    let keys: Vec<String> = table.iter().map(|(key, _)| key.to_string()).collect();
+    for key in keys {
+        let decor = table.key_decor(&key).cloned();
+        let Some(item) = table.remove(&key) else {
+            continue;
+        };
+        let key = if key == old { new.to_string() } else { key };
+        table.insert(&key, item);
+        if let (Some(decor), Some(new_decor)) = (decor, table.key_decor_mut(&key)) {
+            *new_decor = decor;
         }
+    }
+    true
+}
 
-        Ok(())
+// Computes the path of `path` relative to `base`, using `..` when `path`
+// is not inside `base`. Both paths must be absolute.
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path_components: Vec<_> = path.components().collect();
+    let base_components: Vec<_> = base.components().collect();
+    let common = path_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &path_components[common..] {
+        relative.push(component);
     }
 
-    fn get_group_crates(
-        &self,
-        group_patterns: &[String],
-        only_run_top_level: bool,
-    ) -> Result<Vec<&Package>> {
-        let mut crates_by_package = Vec::new();
-        let mut crates_by_path = Vec::new();
-        for pattern in group_patterns {
-            if let Some(path_glob) = pattern.strip_prefix("pkg:") {
-                crates_by_package.push(Glob::new(path_glob)?)
-            } else if let Some(crate_glob) = pattern.strip_prefix("path:") {
-                crates_by_path.push(Glob::new(crate_glob)?)
-            } else {
-                // By default we assume it's a crate glob, like cargo
-                crates_by_path.push(Glob::new(pattern)?)
-            }
-        }
+    relative
+}
 
-        let crates_by_package = Arc::new(make_glob_set(crates_by_package)?);
-        let crates_by_path = Arc::new(make_glob_set(crates_by_path)?);
+fn binary_names(package: &Package) -> Vec<&str> {
+    package
+        .targets
+        .iter()
+        .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+        .map(|target| target.name.as_str())
+        .collect()
+}
 
-        let packages_iter = self
-            .metadata
-            .workspace_packages()
-            .into_iter()
-            .filter(move |package| {
-                crates_by_package.is_match(&package.name)
-                    || crates_by_path.is_match(self.get_package_path_relative_to_workspace(package))
-            });
+// A crate is library-only if it has a library target and no binaries
+fn is_lib_only(package: &Package) -> bool {
+    const LIB_KINDS: [&str; 6] = ["lib", "rlib", "dylib", "cdylib", "staticlib", "proc-macro"];
+    let is_lib = |target: &cargo_metadata::Target| {
+        target
+            .kind
+            .iter()
+            .any(|kind| LIB_KINDS.contains(&kind.as_str()))
+    };
 
-        if only_run_top_level {
-            // Then build a map of the packages that we want to build
-            let mut packages: HashMap<_, _> = packages_iter
-                .clone()
-                .map(|package| (package.name.clone(), package))
-                .collect();
+    package.targets.iter().any(is_lib)
+        && !package
+            .targets
+            .iter()
+            .any(|target| target.kind.iter().any(|kind| kind == "bin"))
+}
 
-            // Then iterate through packages and remove dependent packages,
-            // i.e. if package A depends on package B, we don't need to actively
-            // build package B. This is important because if another package C depends
-            // on a different version of B, we'll get a build error.
-            for package in packages_iter {
-                for dependency in package.dependencies.clone() {
-                    if packages.contains_key(&dependency.name) {
-                        packages.remove(&dependency.name);
-                    }
+// Remove the packages whose name is a declared dependency of another package in the
+// list. This goes by name only, so a crate is also removed when another crate depends
+// on an unrelated crate with the same name, like a registry crate or another version.
+// Dev-dependencies don't count, since building a crate doesn't build them
+fn remove_dependencies(packages: Vec<&Package>) -> Vec<&Package> {
+    let dependency_names: HashSet<&str> = packages
+        .iter()
+        .flat_map(|package| package.dependencies.iter())
+        .filter(|dependency| dependency.kind != DependencyKind::Development)
+        .map(|dependency| dependency.name.as_str())
+        .collect();
+
+    packages
+        .into_iter()
+        .filter(|package| !dependency_names.contains(package.name.as_str()))
+        .collect()
+}
+
+fn package_names(packages: &[&Package]) -> String {
+    packages
+        .iter()
+        .map(|package| package.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Describes the features as they're passed to cargo, for --explain-selection
+fn describe_features(features: &clap_cargo::Features) -> String {
+    let mut parts = Vec::new();
+    if !features.features.is_empty() {
+        parts.push(features.features.join(", "));
+    }
+    if features.all_features {
+        parts.push("all features".to_string());
+    }
+    if features.no_default_features {
+        parts.push("no default features".to_string());
+    }
+    if parts.is_empty() {
+        "default features".to_string()
+    } else {
+        parts.join("; ")
+    }
+}
+
+// Path globs are matched against the crate's directory relative to the workspace
+// root, without the trailing `Cargo.toml`. A leading `/` anchors the glob to the
+// workspace root, which is the same thing, so we strip it to let users write
+// `path:/crates/foo`. The matched path never ends in a `/`, so we strip a trailing
+// one too and `path:crates/foo/` matches the directory. With `strict`, `*` doesn't
+// match `/`, so only `**` matches nested directories.
+fn path_glob(glob: &str, strict: bool) -> Result<Glob> {
+    let glob = glob.strip_prefix('/').unwrap_or(glob);
+    let glob = glob.strip_suffix('/').unwrap_or(glob);
+    Ok(GlobBuilder::new(glob).literal_separator(strict).build()?)
+}
+
+// Replaces `${VAR}` in a pattern with the value of the environment variable, or
+// with `default` for `${VAR:-default}` when the variable isn't set. `$$` is a literal `$`
+fn interpolate_env(pattern: &str) -> Result<String> {
+    let mut result = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(position) = rest.find('$') {
+        result.push_str(&rest[..position]);
+        rest = &rest[position..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or(anyhow::anyhow!("Unclosed ${{ in pattern {}", pattern))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match (env::var(name), default) {
+                (Ok(value), _) => result.push_str(&value),
+                (Err(_), Some(default)) => result.push_str(default),
+                (Err(_), None) => {
+                    return Err(anyhow::anyhow!(
+                        "Environment variable {} used in pattern {} is not set",
+                        name,
+                        pattern
+                    ))
                 }
             }
-
-            Ok(packages.into_iter().map(|(_, package)| package).collect())
+            rest = &after[end + 1..];
         } else {
-            Ok(packages_iter.collect())
+            result.push('$');
+            rest = &rest[1..];
         }
     }
+    result.push_str(rest);
 
-    fn get_package_path_relative_to_workspace(&self, package: &Package) -> PathBuf {
-        package
-            .manifest_path
-            .strip_prefix(self.metadata.workspace_root.as_str())
-            .expect("package path should be child of workspace root")
-            .parent()
-            .unwrap()
-            .into()
+    Ok(result)
+}
+
+// The exit code to exit with when a command finished with this status. Like shells,
+// we use 128 plus the signal number when the command was killed by a signal
+fn exit_code(status: process::ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
     }
 
-    fn execute_on_group<T>(
-        &self,
-        subcommand: &str,
-        group: &str,
-        features: clap_cargo::Features,
-        options: T,
-        // Only run the top level packages, i.e. don't run dependencies
-        // useful for commands like `cargo check` where the dependencies
-        // are checked as part of the top level package, but not so useful
-        // for commands like `cargo test` where the dependencies' tests are
-        // not run.
-        only_run_top_level: bool,
-    ) -> Result<()>
-    where
-        T: Options,
+    1
+}
+
+// Same exit code as the `timeout` utility
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+// The signal we got while running cargo in its own process group, or 0
+static INTERRUPTED_BY: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn record_interrupt(signal: libc::c_int) {
+    INTERRUPTED_BY.store(signal, Ordering::SeqCst);
+}
+
+// Cargo doesn't get Ctrl-C from the terminal when it runs in its own process
+// group, so catch SIGINT and SIGTERM to pass them on to it
+#[cfg(unix)]
+fn catch_interrupts() {
+    let handler = record_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+fn interrupted() -> bool {
+    INTERRUPTED_BY.load(Ordering::SeqCst) != 0
+}
+
+// Dies from the signal we caught, now that the crates it was passed on to have stopped
+fn exit_if_interrupted() {
+    #[cfg(unix)]
     {
-        let Some(crates) = self.cargo_toml.workspace.metadata.groups.get(group) else {
-            return Err(anyhow::anyhow!("Group {} not found", group));
-        };
+        let signal = INTERRUPTED_BY.load(Ordering::SeqCst);
+        if signal != 0 {
+            // SAFETY: restoring the default action and raising a signal have no preconditions
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
+    }
+}
 
-        let cargo = which("cargo")?;
-        let mut cmd = process::Command::new(cargo);
-        cmd.current_dir(&self.cwd).arg(subcommand);
-        add_features(&mut cmd, &features);
-        for member in self.get_group_crates(crates, only_run_top_level)? {
-            cmd.arg("-p").arg(&member.name);
+// Waits for the child to exit, killing it if it runs for longer than the timeout.
+// Returns `None` if the child was killed.
+fn wait_with_timeout(
+    child: &mut process::Child,
+    timeout: Duration,
+) -> Result<Option<process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        #[cfg(unix)]
+        {
+            let signal = INTERRUPTED_BY.load(Ordering::SeqCst);
+            if signal != 0 {
+                // SAFETY: the child leads its own process group, so this only
+                // signals cargo and what it spawned
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), signal);
+                }
+                return Ok(Some(child.wait()?));
+            }
         }
+        thread::sleep(Duration::from_millis(100));
+    }
 
-        options.add_to_command(&mut cmd);
+    #[cfg(unix)]
+    {
+        // SAFETY: the child leads its own process group, so this kills everything it spawned
+        if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+            let err = io::Error::last_os_error();
+            // The group is already gone if the child exited right at the deadline
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                let _ = child.kill();
+                child.wait()?;
+                return Err(anyhow::Error::new(err).context("Failed to kill the timed out command"));
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID"])
+            .arg(child.id().to_string())
+            .status()?;
+    }
+    // Make sure the child itself is gone even if killing the whole tree failed
+    let _ = child.kill();
+    child.wait()?;
 
-        info!("Running command: {:?}", cmd);
+    Ok(None)
+}
+
+struct CrateResult<'a> {
+    package: &'a Package,
+    exit_code: i32,
+    timed_out: bool,
+    elapsed: Duration,
+}
 
-        let result = cmd.spawn()?.wait()?;
+fn print_summary(out: &mut impl Write, results: &[CrateResult]) -> io::Result<()> {
+    let name_width = results
+        .iter()
+        .map(|result| result.package.name.len())
+        .max()
+        .unwrap_or(0);
 
-        process::exit(result.code().unwrap_or(1));
+    writeln!(out)?;
+    for result in results {
+        let status = if result.timed_out {
+            "TIMED OUT".red()
+        } else if result.exit_code == 0 {
+            "ok".green()
+        } else {
+            "FAILED".red()
+        };
+        writeln!(
+            out,
+            "  {:width$} {} {}",
+            result.package.name,
+            status,
+            format!("{:.2}s", result.elapsed.as_secs_f64()).dimmed(),
+            width = name_width
+        )?;
     }
+    let failed = results
+        .iter()
+        .filter(|result| result.exit_code != 0)
+        .count();
+    writeln!(out, "{} passed, {} failed", results.len() - failed, failed)?;
+
+    Ok(())
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    let workspace_info = WorkspaceInfo::from_args(&args)?;
+// The line printed by --summary after a run
+fn print_run_summary(
+    out: &mut impl Write,
+    crates: usize,
+    elapsed: Duration,
+    exit_code: i32,
+) -> io::Result<()> {
+    let status = if exit_code == 0 {
+        "ok".green()
+    } else {
+        format!("failed with exit code {}", exit_code).red()
+    };
+    writeln!(
+        out,
+        "Ran on {} crates in {:.2}s: {}",
+        crates,
+        elapsed.as_secs_f64(),
+        status
+    )
+}
 
-    match args.command {
-        Command::Test {
-            group,
-            features,
-            options,
-        } => workspace_info.execute_on_group("test", &group, features, options, false)?,
-        Command::Build {
-            group,
-            features,
-            options,
-        } => workspace_info.execute_on_group("build", &group, features, options, true)?,
+// Removes ANSI escape sequences like colors from output. The output comes in
+// chunks, so a sequence can be split between two of them
+#[derive(Default)]
+enum AnsiStripper {
+    #[default]
+    Text,
+    // After the escape character
+    Escape,
+    // In a control sequence, until its final byte
+    ControlSequence,
+}
+
+impl AnsiStripper {
+    fn strip(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut stripped = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            *self = match (&self, byte) {
+                (AnsiStripper::Text, b'\x1b') => AnsiStripper::Escape,
+                (AnsiStripper::Text, _) => {
+                    stripped.push(byte);
+                    AnsiStripper::Text
+                }
+                (AnsiStripper::Escape, b'[') => AnsiStripper::ControlSequence,
+                (AnsiStripper::ControlSequence, 0x40..=0x7e) | (AnsiStripper::Escape, _) => {
+                    AnsiStripper::Text
+                }
+                (AnsiStripper::ControlSequence, _) => AnsiStripper::ControlSequence,
+            };
+        }
+        stripped
+    }
+}
+
+// Writes to the terminal and, with --report-file, to the report too.
+// Colors only go to the terminal
+struct ReportWriter<W> {
+    terminal: W,
+    report_file: Option<Arc<Mutex<fs::File>>>,
+    stripper: AnsiStripper,
+}
+
+impl<W: Write> Write for ReportWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.terminal.write(buf)?;
+        if let Some(report_file) = &self.report_file {
+            let stripped = self.stripper.strip(&buf[..written]);
+            report_file
+                .lock()
+                .expect("report file lock poisoned")
+                .write_all(&stripped)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.terminal.flush()
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Writes a coarse JUnit report with one test case per crate. Crates that didn't
+// run because an earlier one failed are marked as skipped
+fn write_junit_report(
+    path: &Path,
+    group: &str,
+    results: &[CrateResult],
+    skipped: &[&Package],
+) -> Result<()> {
+    let failures = results
+        .iter()
+        .filter(|result| result.exit_code != 0)
+        .count();
+    let time: f64 = results
+        .iter()
+        .map(|result| result.elapsed.as_secs_f64())
+        .sum();
+
+    let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    report.push_str(&format!(
+        "<testsuites>\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        escape_xml(group),
+        results.len() + skipped.len(),
+        failures,
+        skipped.len(),
+        time
+    ));
+    for result in results {
+        report.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape_xml(&result.package.name),
+            escape_xml(group),
+            result.elapsed.as_secs_f64()
+        ));
+        if result.timed_out {
+            report.push_str(">\n      <failure message=\"timed out\"/>\n    </testcase>\n");
+        } else if result.exit_code != 0 {
+            report.push_str(&format!(
+                ">\n      <failure message=\"exited with code {}\"/>\n    </testcase>\n",
+                result.exit_code
+            ));
+        } else {
+            report.push_str("/>\n");
+        }
+    }
+    for package in skipped {
+        report.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\">\n      <skipped/>\n    </testcase>\n",
+            escape_xml(&package.name),
+            escape_xml(group)
+        ));
+    }
+    report.push_str("  </testsuite>\n</testsuites>\n");
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, report)?;
+
+    Ok(())
+}
+
+// Quotes the word for a POSIX shell if it contains anything but safe characters
+fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+// A command that would be run, printed by --print-cmd-json and --dry-run
+#[derive(Serialize)]
+struct PlannedCommand {
+    program: String,
+    args: Vec<String>,
+    cwd: Option<PathBuf>,
+    // Variables set to `null` are removed from the environment
+    env: BTreeMap<String, Option<String>>,
+}
+
+impl PlannedCommand {
+    fn new(cmd: &process::Command) -> Self {
+        Self {
+            program: cmd.get_program().to_string_lossy().into_owned(),
+            args: cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cwd: cmd.get_current_dir().map(Path::to_path_buf),
+            env: cmd
+                .get_envs()
+                .map(|(key, value)| {
+                    (
+                        key.to_string_lossy().into_owned(),
+                        value.map(|value| value.to_string_lossy().into_owned()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // Prints the command as JSON, or as a shell command with `shell`
+    fn print(cmd: &process::Command, shell: bool) -> Result<()> {
+        let planned = Self::new(cmd);
+        if shell {
+            println!("{}", planned.to_shell());
+        } else {
+            println!("{}", serde_json::to_string(&planned)?);
+        }
+        Ok(())
+    }
+
+    fn to_shell(&self) -> String {
+        let mut words = Vec::new();
+        if let Some(cwd) = &self.cwd {
+            if current_dir().ok().as_ref() != Some(cwd) {
+                words.push(format!("cd {} &&", shell_quote(&cwd.to_string_lossy())));
+            }
+        }
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => words.push(format!("{}={}", key, shell_quote(value))),
+                None => words.push(format!("env -u {}", key)),
+            }
+        }
+        words.push(shell_quote(&self.program));
+        words.extend(self.args.iter().map(|arg| shell_quote(arg)));
+        words.join(" ")
+    }
+}
+
+// Hashes of the crates that last ran successfully, used by --skip-unchanged.
+// The file maps each command to the hash of every crate it succeeded on.
+struct CrateHashes {
+    path: PathBuf,
+    command_key: String,
+    previous: HashMap<String, HashMap<String, String>>,
+    current: HashMap<PackageId, String>,
+}
+
+impl CrateHashes {
+    fn load(workspace: &WorkspaceInfo, command_key: String, members: &[&Package]) -> Result<Self> {
+        let path = workspace.state_dir().join("crate-hashes.json");
+        let previous = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+
+        // Changes to the lockfile or to a workspace dependency also invalidate a crate
+        let lockfile =
+            fs::read(workspace.metadata.workspace_root.join("Cargo.lock")).unwrap_or_default();
+        let mut dir_hashes = HashMap::new();
+        let mut current = HashMap::new();
+        for member in members {
+            let mut packages: Vec<&Package> = workspace
+                .get_dependency_closure(&[member])?
+                .into_iter()
+                .filter(|id| workspace.metadata.workspace_members.contains(id))
+                .map(|id| &workspace.metadata[id])
+                .collect();
+            packages.push(member);
+            packages.sort_by(|a, b| a.id.cmp(&b.id));
+
+            let mut hasher = DefaultHasher::new();
+            lockfile.hash(&mut hasher);
+            for package in packages {
+                let dir = package
+                    .manifest_path
+                    .parent()
+                    .expect("manifest path should have a parent");
+                if !dir_hashes.contains_key(&package.id) {
+                    let mut dir_hasher = DefaultHasher::new();
+                    hash_dir(dir.as_std_path(), dir.as_std_path(), &mut dir_hasher)?;
+                    dir_hashes.insert(&package.id, dir_hasher.finish());
+                }
+                dir_hashes[&package.id].hash(&mut hasher);
+            }
+            current.insert(member.id.clone(), format!("{:016x}", hasher.finish()));
+        }
+
+        Ok(Self {
+            path,
+            command_key,
+            previous,
+            current,
+        })
+    }
+
+    fn is_unchanged(&self, package: &Package) -> bool {
+        let previous = self
+            .previous
+            .get(&self.command_key)
+            .and_then(|hashes| hashes.get(&package.id.repr));
+        previous.is_some() && previous == self.current.get(&package.id)
+    }
+
+    fn record_success(&mut self, packages: &[&Package]) {
+        let hashes = self.previous.entry(self.command_key.clone()).or_default();
+        for package in packages {
+            if let Some(hash) = self.current.get(&package.id) {
+                hashes.insert(package.id.repr.clone(), hash.clone());
+            }
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string(&self.previous)?)?;
+
+        Ok(())
+    }
+}
+
+// Hashes the paths and contents of the files in a crate directory, skipping
+// build output and hidden files like `.git`
+fn hash_dir(root: &Path, dir: &Path, hasher: &mut DefaultHasher) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let file_name = entry.file_name();
+        if file_name == "target" || file_name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            hash_dir(root, &path, hasher)?;
+        } else {
+            path.strip_prefix(root).unwrap_or(&path).hash(hasher);
+            fs::read(&path)?.hash(hasher);
+        }
+    }
+
+    Ok(())
+}
+
+// The crates matched by each group in earlier runs, stored in the target directory. The
+// cache is thrown away when a manifest or Cargo.lock changes, or a crate is added or removed
+#[derive(Default, Deserialize, Serialize)]
+struct GroupCache {
+    #[serde(skip)]
+    path: PathBuf,
+    manifests: Vec<(PathBuf, u128)>,
+    groups: HashMap<String, CachedGroup>,
+}
+
+// A group's crates, which are only reused when it's matched the same way again
+#[derive(Clone, Deserialize, Serialize)]
+struct CachedGroup {
+    // The patterns after expanding group references and environment variables
+    patterns: Vec<String>,
+    strict_path_globs: bool,
+    dereference_symlinks: bool,
+    packages: Vec<PackageId>,
+    unmatched: Vec<String>,
+}
+
+impl GroupCache {
+    fn load(path: PathBuf, metadata: &cargo_metadata::Metadata) -> Self {
+        let manifests = Self::manifests(metadata);
+        let cache = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Self>(&contents).ok())
+            .filter(|cache| cache.manifests == manifests);
+        match cache {
+            Some(cache) => Self { path, ..cache },
+            None => Self {
+                path,
+                manifests,
+                groups: HashMap::new(),
+            },
+        }
+    }
+
+    // The modification times of the workspace's manifests and lockfile
+    fn manifests(metadata: &cargo_metadata::Metadata) -> Vec<(PathBuf, u128)> {
+        let mut paths = vec![
+            metadata
+                .workspace_root
+                .join("Cargo.toml")
+                .into_std_path_buf(),
+            metadata
+                .workspace_root
+                .join("Cargo.lock")
+                .into_std_path_buf(),
+        ];
+        for package in metadata.workspace_packages() {
+            paths.push(package.manifest_path.clone().into_std_path_buf());
+        }
+        paths.sort();
+        paths.dedup();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let modified = fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_nanos())
+                    .unwrap_or_default();
+                (path, modified)
+            })
+            .collect()
+    }
+
+    // Writes the cache to a temporary file first, so concurrent runs never read half of it
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("cache path has a parent"))?;
+        let temp_path = self.path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&temp_path, serde_json::to_string(self)?)?;
+        fs::rename(temp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+// The invocation that `cargo groups repeat` runs again
+#[derive(Deserialize, Serialize)]
+struct LastCommand {
+    cwd: PathBuf,
+    args: Vec<String>,
+}
+
+struct WorkspaceInfo {
+    cwd: PathBuf,
+    cargo_toml_path: PathBuf,
+    metadata: cargo_metadata::Metadata,
+    cargo_toml: RootCargoToml,
+    execution: ExecutionOptions,
+    dereference_symlinks: bool,
+    strict_path_globs: bool,
+    color: ColorChoice,
+    report_file: Option<Arc<Mutex<fs::File>>>,
+    group_cache: Option<Mutex<GroupCache>>,
+}
+
+impl WorkspaceInfo {
+    fn from_args(args: &Args, metadata_flags: &[&str]) -> Result<Self> {
+        let cwd = args.cwd.clone().unwrap_or_else(|| current_dir().unwrap());
+        let cargo_toml_path = RootCargoToml::find(&cwd, &args.manifest.manifest_path)?;
+        let metadata = MetadataCommand::new()
+            .manifest_path(&cargo_toml_path)
+            .other_options(
+                metadata_flags
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+            )
+            .exec()?;
+        let group_cache = (!args.no_cache).then(|| {
+            let path = metadata
+                .target_directory
+                .join("cargo-groups")
+                .join("groups-cache.json");
+            Mutex::new(GroupCache::load(path.into_std_path_buf(), &metadata))
+        });
+        let cargo_toml = RootCargoToml::load(&cargo_toml_path, args.generated_groups.as_deref())?;
+        let report_file = match &args.execution.report_file {
+            Some(path) => {
+                let file = fs::File::create(path).map_err(|err| {
+                    anyhow::anyhow!("Failed to create {}: {}", path.display(), err)
+                })?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            cwd,
+            cargo_toml_path,
+            metadata,
+            cargo_toml,
+            execution: args.execution.clone(),
+            dereference_symlinks: args.dereference_symlinks,
+            strict_path_globs: args.workspace_relative_globs,
+            color: args.color,
+            report_file,
+            group_cache,
+        })
+    }
+
+    // Prints the group, or the groups whose name matches it as a glob, or every group
+    fn print_groups(
+        &self,
+        out: &mut impl Write,
+        group: Option<&str>,
+        group_separator: Option<&str>,
+    ) -> Result<()> {
+        if group.is_none() && self.cargo_toml.workspace.metadata.groups.is_empty() {
+            writeln!(out, "No groups found")?;
+            return Ok(());
+        }
+
+        let names = self.listed_group_names(group)?;
+        if names.is_empty() {
+            writeln!(out, "No groups matched {}", group.unwrap_or_default())?;
+            return Ok(());
+        }
+        for (index, name) in names.into_iter().enumerate() {
+            if let (Some(separator), true) = (group_separator, index > 0) {
+                writeln!(out, "{}", separator)?;
+            }
+            match self.group_description(name) {
+                Some(description) => writeln!(out, "[{}] {}", name, description.dimmed())?,
+                None => writeln!(out, "[{}]", name)?,
+            }
+            for package in self.get_group_crates(name)? {
+                writeln!(out, "{}", self.package_line(package))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Prints `name: description` for each group, or just the name of groups without one
+    fn print_group_descriptions(&self, out: &mut impl Write, group: Option<&str>) -> Result<()> {
+        for name in self.listed_group_names(group)? {
+            match self.group_description(name) {
+                Some(description) => writeln!(out, "{}: {}", name, description)?,
+                None => writeln!(out, "{}", name)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn group_description(&self, group: &str) -> Option<&str> {
+        self.cargo_toml
+            .workspace
+            .metadata
+            .groups
+            .get(group)
+            .and_then(GroupDefinition::description)
+    }
+
+    // The groups that `list` prints, sorted. A group name prints that group, even if it
+    // contains glob characters, and otherwise the argument is a glob matching group names
+    fn listed_group_names<'a>(&'a self, group: Option<&'a str>) -> Result<Vec<&'a str>> {
+        let groups = &self.cargo_toml.workspace.metadata.groups;
+        let mut names: Vec<&str> = match group {
+            Some(group) if groups.contains_key(group) => return Ok(vec![group]),
+            Some(group) if !group.contains(['*', '?', '[', '{']) => {
+                return Err(anyhow::anyhow!("Group {} not found", group))
+            }
+            Some(glob) => {
+                let matcher = Glob::new(glob)?.compile_matcher();
+                groups
+                    .keys()
+                    .map(String::as_str)
+                    .filter(|name| matcher.is_match(name))
+                    .collect()
+            }
+            None => groups.keys().map(String::as_str).collect(),
+        };
+        names.sort();
+
+        Ok(names)
+    }
+
+    fn print_groups_json(&self, group: Option<&str>) -> Result<()> {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&self.groups_json(group)?)?
+        );
+
+        Ok(())
+    }
+
+    // `{ "groups": { "<group>": [{ "name": ..., "path": ... }] } }`, with only
+    // the groups matching the given group if there is one
+    fn groups_json(&self, group: Option<&str>) -> Result<serde_json::Value> {
+        let names = self.listed_group_names(group)?;
+
+        let mut output = serde_json::Map::new();
+        for name in names {
+            let crates: Vec<_> = self
+                .get_group_crates(name)?
+                .into_iter()
+                .map(|package| {
+                    serde_json::json!({
+                        "name": package.name,
+                        "path": self.get_package_path_relative_to_workspace(package),
+                    })
+                })
+                .collect();
+            output.insert(name.to_string(), crates.into());
+        }
+
+        Ok(serde_json::json!({ "groups": output }))
+    }
+
+    fn print_package(&self, package: &Package) {
+        println!("{}", self.package_line(package));
+    }
+
+    // `  name path`, with the path dimmed
+    fn package_line(&self, package: &Package) -> String {
+        format!(
+            "  {} {}",
+            package.name,
+            self.get_package_path_relative_to_workspace(package)
+                .display()
+                .to_string()
+                .dimmed()
+        )
+    }
+
+    fn print_targets(&self, package: &Package) {
+        let mut targets_by_kind: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for target in &package.targets {
+            for kind in &target.kind {
+                targets_by_kind
+                    .entry(kind.as_str())
+                    .or_default()
+                    .push(target.name.as_str());
+            }
+        }
+
+        self.print_package(package);
+        for (kind, names) in targets_by_kind {
+            println!("    {} {}", format!("{}:", kind).dimmed(), names.join(", "));
+        }
+    }
+
+    fn print_manifest_paths(&self, group: &str, print0: bool) -> Result<()> {
+        let separator = if print0 { '\0' } else { '\n' };
+        let mut stdout = io::stdout().lock();
+        for package in self.get_group_crates(group)? {
+            write!(stdout, "{}{}", package.manifest_path, separator)?;
+        }
+
+        Ok(())
+    }
+
+    fn print_tree(&self, out: &mut impl Write, group: &str) -> Result<()> {
+        let members = self.get_group_crates(group)?;
+        let resolve = self.resolve()?;
+        let in_group: HashMap<&PackageId, &Package> = members
+            .iter()
+            .map(|package| (&package.id, *package))
+            .collect();
+
+        // The dependencies of each crate on other crates of the group
+        let mut dependencies: HashMap<&PackageId, Vec<&Package>> = HashMap::new();
+        for node in &resolve.nodes {
+            if !in_group.contains_key(&node.id) {
+                continue;
+            }
+            let mut group_dependencies: Vec<&Package> = node
+                .deps
+                .iter()
+                .filter(|dependency| {
+                    dependency
+                        .dep_kinds
+                        .iter()
+                        .any(|info| info.kind != DependencyKind::Development)
+                })
+                .filter_map(|dependency| in_group.get(&dependency.pkg).copied())
+                .collect();
+            group_dependencies.sort_by(|a, b| a.name.cmp(&b.name));
+            dependencies.insert(&node.id, group_dependencies);
+        }
+
+        let depended_on: HashSet<&PackageId> = dependencies
+            .values()
+            .flatten()
+            .map(|package| &package.id)
+            .collect();
+        let mut roots: Vec<&Package> = members
+            .iter()
+            .copied()
+            .filter(|package| !depended_on.contains(&package.id))
+            .collect();
+        // Only possible when every crate is part of a cycle
+        if roots.is_empty() {
+            roots = members;
+        }
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+
+        writeln!(out, "[{}]", group)?;
+        let mut printed = HashSet::new();
+        for root in roots {
+            self.print_tree_node(
+                out,
+                root,
+                "",
+                "",
+                &dependencies,
+                &mut Vec::new(),
+                &mut printed,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Prints a crate and its dependencies below it. Crates whose dependencies were already
+    // printed are marked with `(*)`, like `cargo tree` does, and cycles with `(cycle)`
+    #[allow(clippy::too_many_arguments)]
+    fn print_tree_node<'a>(
+        &self,
+        out: &mut impl Write,
+        package: &'a Package,
+        prefix: &str,
+        child_prefix: &str,
+        dependencies: &HashMap<&'a PackageId, Vec<&'a Package>>,
+        path: &mut Vec<&'a PackageId>,
+        printed: &mut HashSet<&'a PackageId>,
+    ) -> io::Result<()> {
+        let line = format!(
+            "{}{} {}",
+            prefix,
+            package.name,
+            self.get_package_path_relative_to_workspace(package)
+                .display()
+                .to_string()
+                .dimmed()
+        );
+        let children = dependencies
+            .get(&package.id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if path.contains(&&package.id) {
+            return writeln!(out, "{} {}", line, "(cycle)".yellow());
+        }
+        if !printed.insert(&package.id) && !children.is_empty() {
+            return writeln!(out, "{} {}", line, "(*)".dimmed());
+        }
+        writeln!(out, "{}", line)?;
+
+        path.push(&package.id);
+        for (index, child) in children.iter().enumerate() {
+            let (branch, indent) = if index + 1 == children.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            self.print_tree_node(
+                out,
+                child,
+                &format!("{}{}", child_prefix, branch),
+                &format!("{}{}", child_prefix, indent),
+                dependencies,
+                path,
+                printed,
+            )?;
+        }
+        path.pop();
+
+        Ok(())
+    }
+
+    fn print_inheritance(&self, group: &str) -> Result<()> {
+        println!("[{}]", group);
+        for package in self.get_group_crates(group)? {
+            let manifest =
+                toml::from_str::<toml::Table>(&fs::read_to_string(&package.manifest_path)?)?;
+
+            self.print_package(package);
+            let inherited = get_inherited_fields(&manifest);
+            if inherited.is_empty() {
+                println!("{}", "    nothing inherited".dimmed());
+            }
+            for field in inherited {
+                println!("    {}", field);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn print_dependency_diff(&self, first: &str, second: &str) -> Result<()> {
+        let first_deps = self.get_external_dependencies(first)?;
+        let second_deps = self.get_external_dependencies(second)?;
+
+        let print_section = |title: String, ids: Vec<&&PackageId>| {
+            println!("[{}]", title);
+            let mut packages: Vec<&Package> =
+                ids.into_iter().map(|id| &self.metadata[*id]).collect();
+            packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+            for package in packages {
+                println!(
+                    "  {} {}",
+                    package.name,
+                    package.version.to_string().dimmed()
+                );
+            }
+        };
+
+        print_section(
+            format!("only in {}", first),
+            first_deps.difference(&second_deps).collect(),
+        );
+        print_section(
+            format!("only in {}", second),
+            second_deps.difference(&first_deps).collect(),
+        );
+        print_section(
+            "shared".to_string(),
+            first_deps.intersection(&second_deps).collect(),
+        );
+
+        Ok(())
+    }
+
+    fn get_external_dependencies(&self, group: &str) -> Result<HashSet<&PackageId>> {
+        let members = self.get_group_crates(group)?;
+        let mut dependencies = self.get_dependency_closure(&members)?;
+        dependencies.retain(|id| !self.metadata.workspace_members.contains(id));
+
+        Ok(dependencies)
+    }
+
+    // Walks the resolved dependency graph to find every package the given
+    // packages depend on, directly or transitively
+    fn get_dependency_closure(&self, packages: &[&Package]) -> Result<HashSet<&PackageId>> {
+        let resolve = self.resolve()?;
+        let nodes: HashMap<&PackageId, &Node> =
+            resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+
+        let mut closure = HashSet::new();
+        let mut stack: Vec<&PackageId> = packages.iter().map(|package| &package.id).collect();
+        while let Some(id) = stack.pop() {
+            let Some(node) = nodes.get(id) else {
+                continue;
+            };
+            for dependency in &node.dependencies {
+                if closure.insert(dependency) {
+                    stack.push(dependency);
+                }
+            }
+        }
+
+        Ok(closure)
+    }
+
+    // Remove the packages that another package in the list depends on, i.e. if package A
+    // depends on package B, we don't need to actively build package B. This is important
+    // because if another package C depends on a different version of B, we'll get a build
+    // error. Unlike `remove_dependencies`, this uses the resolved dependency graph, so
+    // renamed dependencies and crates that share a name with another package are handled
+    // correctly. Dev-dependencies don't count, since building a crate doesn't build them
+    fn remove_resolved_dependencies<'a>(
+        &self,
+        packages: Vec<&'a Package>,
+    ) -> Result<Vec<&'a Package>> {
+        let resolve = self.resolve()?;
+        let selected: HashSet<&PackageId> = packages.iter().map(|package| &package.id).collect();
+        let dependencies: HashSet<&PackageId> = resolve
+            .nodes
+            .iter()
+            .filter(|node| selected.contains(&node.id))
+            .flat_map(|node| node.deps.iter())
+            .filter(|dependency| {
+                dependency
+                    .dep_kinds
+                    .iter()
+                    .any(|info| info.kind != DependencyKind::Development)
+            })
+            .map(|dependency| &dependency.pkg)
+            .collect();
+
+        Ok(packages
+            .into_iter()
+            .filter(|package| !dependencies.contains(&package.id))
+            .collect())
+    }
+
+    // Orders the packages so that each comes after the packages it depends on, directly or
+    // through crates that aren't in the list. Dev-dependencies are ignored, since they may
+    // form cycles and don't need to be built first
+    fn sort_topologically<'a>(&self, packages: Vec<&'a Package>) -> Result<Vec<&'a Package>> {
+        let resolve = self.resolve()?;
+        let nodes: HashMap<&PackageId, &Node> =
+            resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+        let selected: HashMap<&PackageId, &'a Package> = packages
+            .iter()
+            .map(|package| (&package.id, *package))
+            .collect();
+
+        fn visit<'a, 'b>(
+            id: &'b PackageId,
+            nodes: &HashMap<&'b PackageId, &'b Node>,
+            selected: &HashMap<&PackageId, &'a Package>,
+            visiting: &mut Vec<&'b PackageId>,
+            visited: &mut HashSet<&'b PackageId>,
+            sorted: &mut Vec<&'a Package>,
+        ) -> Result<()> {
+            if visited.contains(id) {
+                return Ok(());
+            }
+            if let Some(position) = visiting.iter().position(|visiting_id| *visiting_id == id) {
+                let cycle: Vec<String> = visiting[position..]
+                    .iter()
+                    .chain([&id])
+                    .map(|id| match selected.get(id) {
+                        Some(package) => package.name.clone(),
+                        None => id.repr.clone(),
+                    })
+                    .collect();
+                return Err(anyhow::anyhow!(
+                    "cyclic dependency detected: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+
+            visiting.push(id);
+            if let Some(node) = nodes.get(id) {
+                for dependency in &node.deps {
+                    let is_dev_only = dependency
+                        .dep_kinds
+                        .iter()
+                        .all(|info| info.kind == DependencyKind::Development);
+                    if !is_dev_only {
+                        visit(&dependency.pkg, nodes, selected, visiting, visited, sorted)?;
+                    }
+                }
+            }
+            visiting.pop();
+            visited.insert(id);
+            if let Some(package) = selected.get(id) {
+                sorted.push(package);
+            }
+
+            Ok(())
+        }
+
+        let mut visiting = Vec::new();
+        let mut visited = HashSet::new();
+        let mut sorted = Vec::new();
+        for package in &packages {
+            let Some((id, _)) = nodes.get_key_value(&package.id) else {
+                return Err(anyhow::anyhow!(
+                    "{} is not in the resolved graph",
+                    package.name
+                ));
+            };
+            visit(
+                id,
+                &nodes,
+                &selected,
+                &mut visiting,
+                &mut visited,
+                &mut sorted,
+            )?;
+        }
+
+        Ok(sorted)
+    }
+
+    fn verify_members(&self) -> Result<i32> {
+        let ungrouped = self.get_ungrouped_crates()?;
+        if ungrouped.is_empty() {
+            println!("All crates belong to a group");
+            return Ok(0);
+        }
+
+        println!("{}", "Crates not in any group:".red());
+        for package in ungrouped {
+            self.print_package(package);
+        }
+
+        Ok(1)
+    }
+
+    fn print_orphans(&self, json: bool) -> Result<()> {
+        let ungrouped = self.get_ungrouped_crates()?;
+        if json {
+            let orphans: Vec<_> = ungrouped
+                .iter()
+                .map(|package| {
+                    serde_json::json!({
+                        "name": package.name,
+                        "path": self.get_package_path_relative_to_workspace(package),
+                        "manifest_path": package.manifest_path,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&orphans)?);
+            return Ok(());
+        }
+
+        for package in ungrouped {
+            self.print_package(package);
+        }
+
+        Ok(())
+    }
+
+    fn get_ungrouped_crates(&self) -> Result<Vec<&Package>> {
+        let mut grouped = HashSet::new();
+        for group in self.cargo_toml.workspace.metadata.groups.keys() {
+            for package in self.get_group_crates(group)? {
+                grouped.insert(&package.id);
+            }
+        }
+
+        Ok(self
+            .metadata
+            .workspace_packages()
+            .into_iter()
+            .filter(|package| !grouped.contains(&package.id))
+            .collect())
+    }
+
+    fn print_touched(&self, path: &Path) -> Result<()> {
+        let Some(package) = self.find_owning_package(path) else {
+            println!("{} is not part of any workspace crate", path.display());
+            return Ok(());
+        };
+
+        self.print_package(package);
+        let mut groups = Vec::new();
+        for group in self.cargo_toml.workspace.metadata.groups.keys() {
+            if self
+                .get_group_crates(group)?
+                .iter()
+                .any(|member| member.id == package.id)
+            {
+                groups.push(group);
+            }
+        }
+
+        if groups.is_empty() {
+            println!("{}", "    not in any group".dimmed());
+        }
+        groups.sort();
+        for group in groups {
+            println!("    [{}]", group);
+        }
+
+        Ok(())
+    }
+
+    // Finds the workspace package whose directory contains the path. With nested
+    // packages the innermost one owns the path.
+    fn find_owning_package(&self, path: &Path) -> Option<&Package> {
+        let path = self.cwd.join(path);
+        let path = path.canonicalize().unwrap_or(path);
+
+        self.metadata
+            .workspace_packages()
+            .into_iter()
+            .filter(|package| {
+                package
+                    .manifest_path
+                    .parent()
+                    .is_some_and(|dir| path.starts_with(dir))
+            })
+            .max_by_key(|package| package.manifest_path.components().count())
+    }
+
+    // The crates with files that changed since the git ref, including files that
+    // aren't committed yet
+    fn get_changed_packages(&self, git_ref: &str) -> Result<HashSet<&PackageId>> {
+        let git = |args: &[&str]| -> Result<String> {
+            let output = process::Command::new("git")
+                .args(args)
+                .current_dir(&self.metadata.workspace_root)
+                .output()?;
+            if !output.status.success() {
+                return Err(anyhow::anyhow!(
+                    "git {} failed: {}",
+                    args.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+        let changed_files = git(&["diff", "--name-only", "--relative", git_ref])?;
+        let untracked_files = git(&["ls-files", "--others", "--exclude-standard"])?;
+
+        Ok(changed_files
+            .lines()
+            .chain(untracked_files.lines())
+            .filter_map(|file| {
+                self.find_owning_package(self.metadata.workspace_root.join(file).as_std_path())
+            })
+            .map(|package| &package.id)
+            .collect())
+    }
+
+    fn rename_group(&self, old: &str, new: &str) -> Result<()> {
+        let groups = &self.cargo_toml.workspace.metadata.groups;
+        if !groups.contains_key(old) {
+            return Err(anyhow::anyhow!("Group {} not found", old));
+        }
+        if groups.contains_key(new) {
+            return Err(anyhow::anyhow!("Group {} already exists", new));
+        }
+
+        // Files are only written when the group is defined or referenced in them
+        let cargo_toml_contents = fs::read_to_string(&self.cargo_toml_path)?;
+        let mut document = cargo_toml_contents.parse::<toml_edit::Document>()?;
+        let mut renamed = false;
+        // The group may be defined in both the workspace and the root package
+        for table in ["workspace", "package"] {
+            if let Some(metadata) = document
+                .get_mut(table)
+                .and_then(|table| table.get_mut("metadata"))
+                .and_then(|metadata| metadata.as_table_like_mut())
+            {
+                renamed |= rename_group_in_metadata(metadata, old, new);
+            }
+        }
+        if renamed {
+            fs::write(&self.cargo_toml_path, document.to_string())?;
+        }
+
+        // The group may also be defined or referenced in the configuration file
+        for name in CONFIG_FILES {
+            let config_path = self.cargo_toml_path.with_file_name(name);
+            let Ok(config_contents) = fs::read_to_string(&config_path) else {
+                continue;
+            };
+            let mut document = config_contents.parse::<toml_edit::Document>()?;
+            if rename_group_in_metadata(document.as_table_mut(), old, new) {
+                fs::write(&config_path, document.to_string())?;
+                renamed = true;
+            }
+        }
+        // Like groups generated by a build step, which would only be generated again
+        if !renamed {
+            return Err(anyhow::anyhow!(
+                "Group {} isn't defined in Cargo.toml or {}, so it can't be renamed",
+                old,
+                CONFIG_FILES.join(" or ")
+            ));
+        }
+        println!("Renamed group {} to {}", old, new);
+
+        Ok(())
+    }
+
+    // Interpolates the patterns and replaces `group:` references with the patterns of the
+    // referenced groups. `path` holds the groups being expanded, to detect cycles
+    fn expand_group_references(
+        &self,
+        group_patterns: &[String],
+        path: &mut Vec<String>,
+    ) -> Result<Vec<String>> {
+        let mut patterns = Vec::new();
+        for pattern in group_patterns {
+            let pattern = interpolate_env(pattern)?;
+            // Loading the groups rejects these too, except when they come from an
+            // environment variable
+            if pattern.starts_with("!group:") {
+                let group = path
+                    .last()
+                    .expect("the path starts with the expanded group");
+                return Err(excluded_group_error(group, &pattern));
+            }
+            let Some(group) = pattern.strip_prefix("group:") else {
+                patterns.push(pattern);
+                continue;
+            };
+
+            if let Some(start) = path.iter().position(|visited| visited == group) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(group.to_string());
+                return Err(anyhow::anyhow!(
+                    "cyclic group reference detected: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+            path.push(group.to_string());
+            patterns.extend(self.expand_group_references(self.group_patterns(group)?, path)?);
+            path.pop();
+        }
+
+        Ok(patterns)
+    }
+
+    // The group's patterns with references to other groups expanded. The group starts
+    // the path, so a cycle back to it is reported from where it starts
+    fn expand_group(&self, group: &str) -> Result<Vec<String>> {
+        self.expand_group_references(self.group_patterns(group)?, &mut vec![group.to_string()])
+    }
+
+    fn get_group_crates(&self, group: &str) -> Result<Vec<&Package>> {
+        let patterns = self.expand_group(group)?;
+        let (packages, unmatched) = match self.cached_group_crates(group, &patterns) {
+            Some(matched) => matched,
+            None => {
+                let (packages, unmatched) = self.match_patterns(&patterns)?;
+                self.cache_group_crates(group, patterns, &packages, &unmatched);
+                (packages, unmatched)
+            }
+        };
+        // A pattern that matches nothing is most likely a typo
+        if !unmatched.is_empty() {
+            let patterns = unmatched
+                .iter()
+                .map(|pattern| format!("'{}'", pattern))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if self.execution.strict {
+                return Err(anyhow::anyhow!(
+                    "{} {} matched no workspace packages",
+                    if unmatched.len() == 1 {
+                        "Pattern"
+                    } else {
+                        "Patterns"
+                    },
+                    patterns
+                ));
+            }
+            for pattern in &unmatched {
+                writeln!(
+                    self.stderr(),
+                    "{}",
+                    format!(
+                        "Warning: pattern '{}' matched no workspace packages",
+                        pattern
+                    )
+                    .yellow()
+                )?;
+            }
+        }
+
+        Ok(packages)
+    }
+
+    // The crates that the group's patterns matched in an earlier run, if they haven't changed
+    fn cached_group_crates(
+        &self,
+        group: &str,
+        patterns: &[String],
+    ) -> Option<(Vec<&Package>, Vec<String>)> {
+        let cache = self
+            .group_cache
+            .as_ref()?
+            .lock()
+            .expect("group cache lock poisoned");
+        let cached = cache.groups.get(group)?;
+        if cached.patterns != patterns
+            || cached.strict_path_globs != self.strict_path_globs
+            || cached.dereference_symlinks != self.dereference_symlinks
+        {
+            return None;
+        }
+        let packages = cached
+            .packages
+            .iter()
+            .map(|id| {
+                self.metadata
+                    .workspace_packages()
+                    .into_iter()
+                    .find(|package| &package.id == id)
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some((packages, cached.unmatched.clone()))
+    }
+
+    fn cache_group_crates(
+        &self,
+        group: &str,
+        patterns: Vec<String>,
+        packages: &[&Package],
+        unmatched: &[String],
+    ) {
+        let Some(cache) = &self.group_cache else {
+            return;
+        };
+        let mut cache = cache.lock().expect("group cache lock poisoned");
+        cache.groups.insert(
+            group.to_string(),
+            CachedGroup {
+                patterns,
+                strict_path_globs: self.strict_path_globs,
+                dereference_symlinks: self.dereference_symlinks,
+                packages: packages.iter().map(|package| package.id.clone()).collect(),
+                unmatched: unmatched.to_vec(),
+            },
+        );
+        // The cache is only an optimization, so failing to write it is fine
+        let _ = cache.save();
+    }
+
+    // Returns the crates matched by the expanded patterns, along with the patterns
+    // that don't match any crate in the workspace
+    fn match_patterns(&self, patterns: &[String]) -> Result<(Vec<&Package>, Vec<String>)> {
+        // Each pattern is matched on its own to know which ones match nothing
+        let mut matchers = Vec::new();
+        for pattern in patterns.iter().cloned() {
+            // Patterns starting with `!` remove crates from the ones matched by the others
+            let (glob, excluded) = match pattern.strip_prefix('!') {
+                Some(glob) => (glob, true),
+                None => (pattern.as_str(), false),
+            };
+            let matcher = if glob == "members" || glob == "workspace:*" {
+                PatternMatcher::All
+            } else if let Some(package_glob) = glob.strip_prefix("pkg:") {
+                PatternMatcher::Name(Glob::new(package_glob)?.compile_matcher())
+            } else if let Some(package_glob) = glob.strip_prefix("deps:") {
+                let roots = self.packages_named(package_glob)?;
+                PatternMatcher::Ids(self.with_workspace_dependencies(roots)?)
+            } else if let Some(package_glob) = glob.strip_prefix("rdeps:") {
+                let roots = self.packages_named(package_glob)?;
+                PatternMatcher::Ids(self.with_workspace_dependents(roots)?)
+            } else if let Some(crate_glob) = glob.strip_prefix("path:") {
+                PatternMatcher::Path(
+                    path_glob(crate_glob, self.strict_path_globs)?.compile_matcher(),
+                )
+            } else {
+                // By default we assume it's a crate glob, like cargo
+                PatternMatcher::Path(path_glob(glob, self.strict_path_globs)?.compile_matcher())
+            };
+            matchers.push((pattern, excluded, matcher));
+        }
+
+        let mut matched = vec![false; matchers.len()];
+        let mut packages = Vec::new();
+        for package in self.metadata.workspace_packages() {
+            let match_path = self.get_package_match_path(package);
+            let mut included = false;
+            let mut excluded = false;
+            for (index, (_, is_exclusion, matcher)) in matchers.iter().enumerate() {
+                let is_match = match matcher {
+                    PatternMatcher::Name(matcher) => matcher.is_match(package.name.as_str()),
+                    PatternMatcher::Path(matcher) => matcher.is_match(&match_path),
+                    PatternMatcher::Ids(ids) => ids.contains(&package.id),
+                    PatternMatcher::All => true,
+                };
+                if is_match {
+                    matched[index] = true;
+                    if *is_exclusion {
+                        excluded = true;
+                    } else {
+                        included = true;
+                    }
+                }
+            }
+            if included && !excluded {
+                packages.push(package);
+            }
+        }
+
+        let unmatched = matchers
+            .into_iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|((pattern, ..), _)| pattern)
+            .collect();
+
+        Ok((packages, unmatched))
+    }
+
+    // The ids of the workspace packages whose name matches the glob
+    fn packages_named(&self, glob: &str) -> Result<Vec<&PackageId>> {
+        let matcher = Glob::new(glob)?.compile_matcher();
+        Ok(self
+            .metadata
+            .workspace_packages()
+            .into_iter()
+            .filter(|package| matcher.is_match(package.name.as_str()))
+            .map(|package| &package.id)
+            .collect())
+    }
+
+    // The packages along with the workspace packages that depend on them, directly or
+    // through other workspace packages. Dev-dependencies count, since a change to a
+    // crate can break the tests of crates that only use it in their tests
+    fn with_workspace_dependents<'a>(
+        &'a self,
+        roots: Vec<&'a PackageId>,
+    ) -> Result<HashSet<&'a PackageId>> {
+        let resolve = self.resolve()?;
+        let workspace_members: HashSet<&PackageId> =
+            self.metadata.workspace_members.iter().collect();
+        let mut dependents: HashMap<&PackageId, Vec<&PackageId>> = HashMap::new();
+        for node in &resolve.nodes {
+            if !workspace_members.contains(&node.id) {
+                continue;
+            }
+            for dependency in &node.deps {
+                dependents
+                    .entry(&dependency.pkg)
+                    .or_default()
+                    .push(&node.id);
+            }
+        }
+
+        let mut packages = HashSet::new();
+        let mut stack = roots;
+        while let Some(id) = stack.pop() {
+            if packages.insert(id) {
+                stack.extend(dependents.get(id).into_iter().flatten());
+            }
+        }
+
+        Ok(packages)
+    }
+
+    // The packages along with the workspace packages they depend on, directly or
+    // through other workspace packages. Dev-dependencies aren't followed
+    fn with_workspace_dependencies<'a>(
+        &'a self,
+        roots: Vec<&'a PackageId>,
+    ) -> Result<HashSet<&'a PackageId>> {
+        let resolve = self.resolve()?;
+        let nodes: HashMap<&PackageId, &Node> =
+            resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+        let workspace_members: HashSet<&PackageId> =
+            self.metadata.workspace_members.iter().collect();
+
+        let mut packages = HashSet::new();
+        let mut stack = roots;
+        while let Some(id) = stack.pop() {
+            if !packages.insert(id) {
+                continue;
+            }
+            let Some(node) = nodes.get(id) else {
+                continue;
+            };
+            for dependency in &node.deps {
+                let is_dev_only = dependency
+                    .dep_kinds
+                    .iter()
+                    .all(|info| info.kind == DependencyKind::Development);
+                if !is_dev_only && workspace_members.contains(&dependency.pkg) {
+                    stack.push(&dependency.pkg);
+                }
+            }
+        }
+
+        Ok(packages)
+    }
+
+    // The path that `path:` patterns are matched against
+    fn get_package_match_path(&self, package: &Package) -> PathBuf {
+        if !self.dereference_symlinks {
+            return self.get_package_path_relative_to_workspace(package);
+        }
+
+        let package_dir = package
+            .manifest_path
+            .parent()
+            .expect("manifest path should have a parent")
+            .canonicalize();
+        let workspace_root = self.metadata.workspace_root.canonicalize();
+        match (package_dir, workspace_root) {
+            // The symlink may point outside of the workspace, in which case
+            // the path starts with `..`
+            (Ok(package_dir), Ok(workspace_root)) => relative_path(&package_dir, &workspace_root),
+            _ => self.get_package_path_relative_to_workspace(package),
+        }
+    }
+
+    // The directories of the crates in the group, which watch listens to for changes.
+    // Crates nested in another crate of the group are already walked with it
+    fn watched_dirs(&self, group: &str) -> Result<Vec<PathBuf>> {
+        let members = self.get_group_crates(group)?;
+        let workspace_root = Path::new(self.metadata.workspace_root.as_str());
+        let dirs: Vec<PathBuf> = members
+            .into_iter()
+            .map(|package| {
+                workspace_root.join(self.get_package_path_relative_to_workspace(package))
+            })
+            .collect();
+        Ok(dirs
+            .iter()
+            .filter(|dir| {
+                !dirs
+                    .iter()
+                    .any(|other| other != *dir && dir.starts_with(other))
+            })
+            .cloned()
+            .collect())
+    }
+
+    // The directory containing the crate's manifest, e.g. `crates/foo`
+    fn get_package_path_relative_to_workspace(&self, package: &Package) -> PathBuf {
+        package
+            .manifest_path
+            .strip_prefix(self.metadata.workspace_root.as_str())
+            .expect("package path should be child of workspace root")
+            .parent()
+            .unwrap()
+            .into()
+    }
+
+    fn execute_on_group<T>(
+        &self,
+        subcommand: &str,
+        selection: &Selection,
+        features: clap_cargo::Features,
+        options: T,
+        only_run_top_level: bool,
+    ) -> Result<i32>
+    where
+        T: Options,
+    {
+        self.with_hooks(|| {
+            self.run_on_group(subcommand, selection, features, options, only_run_top_level)
+        })
+    }
+
+    // Runs the pre and post commands around `run`
+    fn with_hooks(&self, run: impl FnOnce() -> Result<i32>) -> Result<i32> {
+        // Nothing runs when only printing the commands
+        if self.execution.prints_commands() {
+            return run();
+        }
+
+        if self.execution.require_clean_worktree {
+            self.ensure_clean_worktree()?;
+        }
+
+        if let Some(pre_command) = &self.execution.pre_command {
+            let code = self.run_hook(pre_command)?;
+            if code != 0 {
+                writeln!(
+                    self.stderr(),
+                    "{}",
+                    format!("Pre-command failed: {}", pre_command).red()
+                )?;
+                return Ok(code);
+            }
+        }
+
+        let code = run()?;
+
+        if let Some(post_command) = &self.execution.post_command {
+            let post_code = self.run_hook(post_command)?;
+            if post_code != 0 {
+                writeln!(
+                    self.stderr(),
+                    "{}",
+                    format!("Post-command failed: {}", post_command).red()
+                )?;
+                if code == 0 {
+                    return Ok(post_code);
+                }
+            }
+        }
+
+        Ok(code)
+    }
+
+    fn ensure_clean_worktree(&self) -> Result<()> {
+        let output = process::Command::new("git")
+            .args(["status", "--porcelain"])
+            .current_dir(&self.metadata.workspace_root)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Failed to check the git worktree: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        if !output.stdout.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The git worktree has uncommitted changes:\n{}",
+                String::from_utf8_lossy(&output.stdout).trim_end()
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Unstable flags are only accepted by nightly toolchains, or by any toolchain
+    // with RUSTC_BOOTSTRAP set
+    fn ensure_nightly(&self) -> Result<()> {
+        if env::var_os("RUSTC_BOOTSTRAP").is_some() {
+            return Ok(());
+        }
+
+        let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let output = process::Command::new(rustc)
+            .arg("--version")
+            .current_dir(&self.cwd)
+            .output()?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || !(version.contains("-nightly") || version.contains("-dev")) {
+            return Err(anyhow::anyhow!(
+                "Unstable (-Z) flags require a nightly toolchain, but the active one is {}. \
+                 Try `cargo +nightly groups ...`",
+                version.trim()
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Asks whether to run on the crates when --confirm is passed
+    fn confirm(&self, group: &str, members: &[&Package]) -> Result<bool> {
+        if !self.execution.confirm || self.execution.yes || !io::stdin().is_terminal() {
+            return Ok(true);
+        }
+
+        eprintln!("This will run on {} crates in {}:", members.len(), group);
+        for member in members {
+            eprintln!("  {}", member.name);
+        }
+        eprint!("Continue? [y/N] ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+        if !confirmed {
+            eprintln!("Aborted");
+        }
+
+        Ok(confirmed)
+    }
+
+    fn execute_custom_command(&self, args: &[String]) -> Result<i32> {
+        let [name, group, extra_args @ ..] = args else {
+            return Err(anyhow::anyhow!(
+                "Usage: cargo groups {} <GROUP> [ARGS]...",
+                args.first().map(String::as_str).unwrap_or("<COMMAND>")
+            ));
+        };
+        let template = self
+            .cargo_toml
+            .workspace
+            .metadata
+            .groups_commands
+            .get(name)
+            .ok_or(anyhow::anyhow!("Unknown command {}", name))?;
+
+        let members = self.get_group_crates(group)?;
+        let mut cmd =
+            self.cargo_command_with_args(&expand_command_template(template, group, &members)?)?;
+        cmd.args(extra_args);
+        self.run_group_command(group, &members, cmd)
+    }
+
+    // Runs any cargo command on a group, with the arguments given verbatim. Dependencies
+    // aren't skipped, since not every command covers them, e.g. `cargo tree`
+    fn execute_exec(&self, group: &str, args: &[String]) -> Result<i32> {
+        let members = self.get_group_crates(group)?;
+        let cmd = self.exec_command(&members, args)?;
+        self.run_group_command(group, &members, cmd)
+    }
+
+    // `cargo <args>` with the crates selected before `--`, after which the
+    // arguments are for whatever cargo runs
+    fn exec_command(&self, members: &[&Package], args: &[String]) -> Result<process::Command> {
+        let (cargo_args, trailing_args) = match args.iter().position(|arg| arg == "--") {
+            Some(position) => args.split_at(position),
+            None => (args, &[][..]),
+        };
+        let mut cmd = self.cargo_command_with_args(cargo_args)?;
+        for member in members {
+            cmd.arg("-p").arg(&member.name);
+        }
+        cmd.args(trailing_args);
+        Ok(cmd)
+    }
+
+    fn run_group_command(
+        &self,
+        group: &str,
+        members: &[&Package],
+        mut cmd: process::Command,
+    ) -> Result<i32> {
+        if !self.execution.prints_commands() && !self.confirm(group, members)? {
+            return Ok(1);
+        }
+        self.add_group_env(&mut cmd, &[group.to_string()]);
+
+        self.with_hooks(|| {
+            if self.execution.prints_commands() {
+                PlannedCommand::print(&cmd, self.execution.dry_run)?;
+                return Ok(0);
+            }
+            self.run_command(&mut cmd)
+        })
+    }
+
+    fn run_hook(&self, command: &str) -> Result<i32> {
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = process::Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        } else {
+            let mut cmd = process::Command::new("sh");
+            cmd.arg("-c");
+            cmd
+        };
+        cmd.arg(command).current_dir(&self.metadata.workspace_root);
+        info!("Running hook: {:?}", cmd);
+
+        Ok(exit_code(cmd.spawn()?.wait()?))
+    }
+
+    // Applies the options from the profile passed with --profile-name and the groups' defaults
+    fn apply_configured_options<T: Options>(
+        &self,
+        groups: &[String],
+        features: &mut clap_cargo::Features,
+        options: &mut T,
+    ) -> Result<()> {
+        let metadata = &self.cargo_toml.workspace.metadata;
+        if let Some(profile_name) = &self.execution.profile_name {
+            let profile = metadata
+                .groups_profiles
+                .get(profile_name)
+                .ok_or(anyhow::anyhow!("Profile {} not found", profile_name))?;
+            profile.add_to_features(features);
+            options.apply_defaults(profile);
+            self.explain_defaults(&format!("profile {}", profile_name), profile)?;
+        }
+        for group in groups {
+            if let Some(defaults) = metadata.groups_defaults.get(group) {
+                defaults.add_to_features(features);
+                self.explain_defaults(&format!("the defaults of group {}", group), defaults)?;
+                options.apply_defaults(defaults);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Prints the features configured options add, for --explain-selection
+    fn explain_defaults(&self, source: &str, defaults: &DefaultOptions) -> Result<()> {
+        if !self.execution.explain_selection {
+            return Ok(());
+        }
+
+        let mut features = clap_cargo::Features::default();
+        defaults.add_to_features(&mut features);
+        writeln!(
+            self.stderr(),
+            "  Features from {}: {}",
+            source,
+            describe_features(&features)
+        )?;
+
+        Ok(())
+    }
+
+    // Prints the environment variables that building the group would set for cargo
+    fn print_env<T: Options>(
+        &self,
+        selection: &Selection,
+        mut features: clap_cargo::Features,
+        mut options: T,
+    ) -> Result<()> {
+        if self.execution.explain_selection {
+            self.explain_patterns(selection)?;
+        }
+        let groups = self.selected_groups(selection)?;
+        self.apply_configured_options(&groups, &mut features, &mut options)?;
+        let members = options.select_members(self.select_crates(selection, false)?)?;
+        let mut cmd = self.build_command("build", &features, &options, &members)?;
+        self.add_group_env(&mut cmd, &groups);
+
+        let mut envs: Vec<_> = cmd.get_envs().collect();
+        envs.sort();
+        if envs.is_empty() {
+            eprintln!(
+                "{}",
+                format!("No environment overrides for {}", selection.name()).dimmed()
+            );
+        }
+        for (key, value) in envs {
+            match value {
+                Some(value) => println!("{}={}", key.to_string_lossy(), value.to_string_lossy()),
+                None => println!("unset {}", key.to_string_lossy()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_on_group<T>(
+        &self,
+        subcommand: &str,
+        selection: &Selection,
+        mut features: clap_cargo::Features,
+        mut options: T,
+        // Only run the top level packages, i.e. don't run dependencies
+        // useful for commands like `cargo check` where the dependencies
+        // are checked as part of the top level package, but not so useful
+        // for commands like `cargo test` where the dependencies' tests are
+        // not run.
+        only_run_top_level: bool,
+    ) -> Result<i32>
+    where
+        T: Options,
+    {
+        let group = &selection.name();
+        if self.execution.stdin_features {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            for feature in input
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|feature| !feature.is_empty())
+            {
+                if !features.features.iter().any(|f| f == feature) {
+                    features.features.push(feature.to_string());
+                }
+            }
+        }
+        if self.execution.explain_selection {
+            self.explain_patterns(selection)?;
+        }
+        let groups = self.selected_groups(selection)?;
+        self.apply_configured_options(&groups, &mut features, &mut options)?;
+
+        // Commands like publish always run per crate, so check the flags
+        // against the mode we end up in rather than against --per-crate
+        let per_crate = self.execution.per_crate || options.per_crate();
+        if !per_crate {
+            if let Some(flag) = self.execution.per_crate_flag() {
+                return Err(anyhow::anyhow!("{} requires --per-crate", flag));
+            }
+            if options.junit_path().is_some() {
+                return Err(anyhow::anyhow!(
+                    "--junit requires --per-crate or --isolate-features"
+                ));
+            }
+        } else if let Some(flag) = self.execution.warnings_flag() {
+            // Warnings are only counted in the output of a single cargo invocation
+            return Err(anyhow::anyhow!("{} can't be used in per-crate mode", flag));
+        }
+        if options.sequential() && self.execution.group_jobs.is_some_and(|jobs| jobs > 1) {
+            return Err(anyhow::anyhow!(
+                "--group-jobs can't be used with {}, which runs one crate at a time",
+                subcommand
+            ));
+        }
+
+        let selected = self.select_crates(selection, only_run_top_level)?;
+        let members = options.select_members(selected.clone())?;
+        if members.len() != selected.len() {
+            self.explain_removed(&format!("Removed by {}", subcommand), &selected, &members)?;
+        }
+        // The selection can be empty when there are more partitions than crates,
+        // or when every crate changed with --exclude-changed
+        if members.is_empty() {
+            writeln!(self.stdout(), "No crates to run on in {}", group)?;
+            return Ok(0);
+        }
+        // Resume before skipping unchanged crates, so --from can name a crate
+        // that would be skipped
+        let members = match &self.execution.from {
+            Some(from) => {
+                let Some(position) = members.iter().position(|member| &member.name == from) else {
+                    return Err(anyhow::anyhow!("Crate {} is not in group {}", from, group));
+                };
+                members[position..].to_vec()
+            }
+            None => members,
+        };
+
+        if self.execution.print_targets {
+            for member in &members {
+                self.print_targets(member);
+            }
+            return Ok(0);
+        }
+        let lib_only = options.auto_lib() && members.iter().all(|member| is_lib_only(member));
+        if lib_only {
+            writeln!(
+                self.stderr(),
+                "{}",
+                format!("All crates in {} are libraries, passing --lib", group).dimmed()
+            )?;
+        }
+
+        let mut crate_hashes = None;
+        let members = if self.execution.skip_unchanged {
+            // The hashes are only valid for the exact same command
+            let command_key = format!(
+                "{:?}",
+                self.build_command(subcommand, &features, &options, &[])?
+            );
+            let hashes = CrateHashes::load(self, command_key, &members)?;
+            let (unchanged, changed): (Vec<&Package>, Vec<&Package>) = members
+                .iter()
+                .partition(|member| hashes.is_unchanged(member));
+            self.explain_removed("Removed as unchanged", &members, &changed)?;
+            crate_hashes = Some(hashes);
+
+            if !unchanged.is_empty() {
+                writeln!(
+                    self.stderr(),
+                    "{}",
+                    format!(
+                        "Skipping {} unchanged crates: {}",
+                        unchanged.len(),
+                        unchanged
+                            .iter()
+                            .map(|member| member.name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                    .dimmed()
+                )?;
+            }
+            if changed.is_empty() {
+                writeln!(self.stdout(), "All crates in {} are unchanged", group)?;
+                return Ok(0);
+            }
+            changed
+        } else {
+            members
+        };
+
+        if self.execution.explain_selection {
+            writeln!(
+                self.stderr(),
+                "  Features: {}",
+                describe_features(&features)
+            )?;
+            writeln!(
+                self.stderr(),
+                "  Crates: {}",
+                members
+                    .iter()
+                    .map(|member| format!("-p {}", member.name))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )?;
+        }
+
+        if !self.execution.prints_commands() && !self.confirm(group, &members)? {
+            return Ok(1);
+        }
+
+        // Fail early instead of with cargo's error about -Z flags on stable
+        let command = self.build_command(subcommand, &features, &options, &[])?;
+        if !self.execution.prints_commands() && command.get_args().any(|arg| arg == "-Z") {
+            self.ensure_nightly()?;
+        }
+        self.create_artifact_dir(&options)?;
+
+        if !per_crate {
+            let mut cmd = self.build_command(subcommand, &features, &options, &members)?;
+            self.add_group_env(&mut cmd, &groups);
+            if lib_only {
+                cmd.arg("--lib");
+            }
+            if self.execution.prints_commands() {
+                PlannedCommand::print(&cmd, self.execution.dry_run)?;
+                return Ok(0);
+            }
+            let started = Instant::now();
+            let code = if self.execution.counts_warnings() {
+                self.run_with_warning_checks(&mut cmd, group)?
+            } else {
+                self.run_command(&mut cmd)?
+            };
+            if let Some(mut hashes) = crate_hashes {
+                if code == 0 {
+                    hashes.record_success(&members);
+                    hashes.save()?;
+                }
+            }
+            if self.execution.summary {
+                print_run_summary(&mut self.stdout(), members.len(), started.elapsed(), code)?;
+            }
+
+            return Ok(code);
+        }
+
+        if self.execution.prints_commands() {
+            for member in &members {
+                let mut cmd = self.build_command(subcommand, &features, &options, &[member])?;
+                self.add_group_env(&mut cmd, &groups);
+                if lib_only {
+                    cmd.arg("--lib");
+                }
+                PlannedCommand::print(&cmd, self.execution.dry_run)?;
+            }
+            return Ok(0);
+        }
+
+        let jobserver = if self.execution.jobserver {
+            let tokens = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+            Some(jobserver::Client::new(tokens)?)
+        } else {
+            None
+        };
+        // Workers take crates from the queue until it's empty or a crate fails
+        let queue = Mutex::new(members.iter().copied());
+        let results = Mutex::new(Vec::new());
+        let failed = AtomicBool::new(false);
+        let started_at = Instant::now();
+        let delay = options.delay_between_crates();
+        let group_jobs = if options.sequential() {
+            1
+        } else {
+            self.execution.group_jobs.unwrap_or(1).max(1)
+        };
+        let started = AtomicBool::new(false);
+        thread::scope(|scope| -> Result<()> {
+            let workers: Vec<_> = (0..group_jobs)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        while !failed.load(Ordering::SeqCst) {
+                            let Some(member) = queue.lock().expect("queue lock poisoned").next()
+                            else {
+                                break;
+                            };
+                            if let Some(delay) = delay {
+                                if started.swap(true, Ordering::SeqCst) {
+                                    writeln!(
+                                        self.stderr(),
+                                        "{}",
+                                        format!("Waiting {}s", delay.as_secs()).dimmed()
+                                    )?;
+                                    thread::sleep(delay);
+                                }
+                            }
+                            let mut cmd =
+                                self.build_command(subcommand, &features, &options, &[member])?;
+                            self.add_group_env(&mut cmd, &groups);
+                            if lib_only {
+                                cmd.arg("--lib");
+                            }
+                            if let Some(jobserver) = &jobserver {
+                                jobserver.configure_make(&mut cmd);
+                            }
+                            let result = self.run_crate(member, &mut cmd)?;
+                            if interrupted()
+                                || result.exit_code != 0 && !self.execution.continue_on_error
+                            {
+                                failed.store(true, Ordering::SeqCst);
+                            }
+                            results.lock().expect("results lock poisoned").push(result);
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().expect("worker panicked")?;
+            }
+            Ok(())
+        })?;
+        exit_if_interrupted();
+        let mut results = results.into_inner().expect("results lock poisoned");
+        results.sort_by_key(|result| {
+            members
+                .iter()
+                .position(|member| member.id == result.package.id)
+        });
+
+        if self.execution.summary_only
+            || self.execution.timeout.is_some()
+            || self.execution.continue_on_error
+            || self.execution.summary
+        {
+            print_summary(&mut self.stdout(), &results)?;
+        }
+
+        if let Some(path) = options.junit_path() {
+            let skipped: Vec<&Package> = members
+                .iter()
+                .copied()
+                .filter(|member| !results.iter().any(|result| result.package.id == member.id))
+                .collect();
+            write_junit_report(path, group, &results, &skipped)?;
+        }
+
+        if let Some(mut hashes) = crate_hashes {
+            let succeeded: Vec<&Package> = results
+                .iter()
+                .filter(|result| result.exit_code == 0)
+                .map(|result| result.package)
+                .collect();
+            hashes.record_success(&succeeded);
+            hashes.save()?;
+        }
+
+        let code = results
+            .iter()
+            .map(|result| result.exit_code)
+            .find(|code| *code != 0)
+            .unwrap_or(0);
+        if self.execution.summary {
+            print_run_summary(
+                &mut self.stdout(),
+                members.len(),
+                started_at.elapsed(),
+                code,
+            )?;
+        }
+
+        Ok(code)
+    }
+
+    fn run_crate<'a>(
+        &self,
+        member: &'a Package,
+        cmd: &mut process::Command,
+    ) -> Result<CrateResult<'a>> {
+        let span = info_span!("crate", crate_name = %member.name);
+        let _guard = span.enter();
+        info!(crate_name = %member.name, "Starting crate");
+        info!("Running command: {:?}", cmd);
+
+        let start = Instant::now();
+        let (code, timed_out) = self.run_crate_command(cmd)?;
+        let elapsed = start.elapsed();
+        info!(
+            crate_name = %member.name,
+            elapsed_ms = elapsed.as_millis() as u64,
+            exit_code = code,
+            "Finished crate"
+        );
+
+        Ok(CrateResult {
+            package: member,
+            exit_code: code,
+            timed_out,
+            elapsed,
+        })
+    }
+
+    fn stdout(&self) -> ReportWriter<io::Stdout> {
+        self.report_writer(io::stdout())
+    }
+
+    fn stderr(&self) -> ReportWriter<io::Stderr> {
+        self.report_writer(io::stderr())
+    }
+
+    // Writes to the report file only
+    fn report(&self) -> ReportWriter<io::Sink> {
+        self.report_writer(io::sink())
+    }
+
+    fn report_writer<W: Write>(&self, terminal: W) -> ReportWriter<W> {
+        ReportWriter {
+            terminal,
+            report_file: self.report_file.clone(),
+            stripper: AnsiStripper::default(),
+        }
+    }
+
+    // Writes a line to the report file only
+    fn report_line(&self, line: &str) -> Result<()> {
+        writeln!(self.report(), "{}", line)?;
+        Ok(())
+    }
+
+    // Pipes the output of the command, since cargo can't tell that it ends up on our terminal
+    fn pipe_output(&self, cmd: &mut process::Command) {
+        if colored::control::SHOULD_COLORIZE.should_colorize()
+            && env::var_os("CARGO_TERM_COLOR").is_none()
+        {
+            cmd.env("CARGO_TERM_COLOR", "always");
+        }
+        cmd.stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped());
+    }
+
+    // Copies the child's output to the terminal and the report file as it comes in
+    fn tee_output(&self, child: &mut process::Child) -> Vec<thread::JoinHandle<io::Result<u64>>> {
+        let mut threads = Vec::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            let mut out = self.stdout();
+            threads.push(thread::spawn(move || io::copy(&mut stdout, &mut out)));
+        }
+        if let Some(mut stderr) = child.stderr.take() {
+            let mut out = self.stderr();
+            threads.push(thread::spawn(move || io::copy(&mut stderr, &mut out)));
+        }
+        threads
+    }
+
+    // Runs the command to completion, returning its exit code
+    fn run_command(&self, cmd: &mut process::Command) -> Result<i32> {
+        info!("Running command: {:?}", cmd);
+        self.report_line(&format!("Running command: {:?}", cmd))?;
+        if self.report_file.is_none() {
+            return Ok(exit_code(cmd.spawn()?.wait()?));
+        }
+
+        self.pipe_output(cmd);
+        let mut child = cmd.spawn()?;
+        let threads = self.tee_output(&mut child);
+        let status = child.wait()?;
+        for thread in threads {
+            thread.join().expect("output reader panicked")?;
+        }
+
+        Ok(exit_code(status))
+    }
+
+    fn baseline_path(&self) -> PathBuf {
+        self.execution
+            .baseline_file
+            .clone()
+            .unwrap_or_else(|| self.state_dir().join("warnings-baseline.json"))
+    }
+
+    // Runs the command while counting the warnings of each crate, returning the
+    // exit code and the number of warnings by crate name
+    fn run_counting_warnings(
+        &self,
+        cmd: &mut process::Command,
+    ) -> Result<(i32, BTreeMap<String, usize>)> {
+        info!("Running command: {:?}", cmd);
+        self.report_line(&format!("Running command: {:?}", cmd))?;
+        cmd.stdout(process::Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let stdout = io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+
+        let package_names: HashMap<&PackageId, &str> = self
+            .metadata
+            .workspace_packages()
+            .into_iter()
+            .map(|package| (&package.id, package.name.as_str()))
+            .collect();
+        let mut warnings: BTreeMap<String, usize> = BTreeMap::new();
+        for line in io::BufRead::lines(stdout) {
+            let line = line?;
+            let Ok(message) = serde_json::from_str::<cargo_metadata::Message>(&line) else {
+                // Output that isn't from cargo, like test binaries
+                writeln!(self.stdout(), "{}", line)?;
+                continue;
+            };
+            if let cargo_metadata::Message::CompilerMessage(message) = message {
+                if let Some(rendered) = &message.message.rendered {
+                    write!(self.stderr(), "{}", rendered)?;
+                }
+                if message.message.level == cargo_metadata::diagnostic::DiagnosticLevel::Warning {
+                    if let Some(name) = package_names.get(&message.package_id) {
+                        *warnings.entry(name.to_string()).or_default() += 1;
+                    }
+                }
+            }
+        }
+        let code = exit_code(child.wait()?);
+
+        Ok((code, warnings))
+    }
+
+    // Runs the command, then checks its warnings against --fail-on-warnings-count,
+    // and saves them as the group's baseline or compares them against it
+    fn run_with_warning_checks(&self, cmd: &mut process::Command, group: &str) -> Result<i32> {
+        let (code, warnings) = self.run_counting_warnings(cmd)?;
+        if code != 0 {
+            return Ok(code);
+        }
+
+        if let Some(max_warnings) = self.execution.fail_on_warnings_count {
+            let total: usize = warnings.values().sum();
+            if total > max_warnings {
+                writeln!(
+                    self.stdout(),
+                    "{}",
+                    format!(
+                        "{} has {} warnings, more than the limit of {}",
+                        group, total, max_warnings
+                    )
+                    .red()
+                )?;
+                return Ok(1);
+            }
+        }
+        if !self.execution.save_baseline && !self.execution.compare_baseline {
+            return Ok(0);
+        }
+
+        let path = self.baseline_path();
+        let mut baselines: BTreeMap<String, BTreeMap<String, usize>> =
+            match fs::read_to_string(&path) {
+                Ok(contents) => serde_json::from_str(&contents)?,
+                Err(_) => BTreeMap::new(),
+            };
+
+        if self.execution.save_baseline {
+            let total: usize = warnings.values().sum();
+            baselines.insert(group.to_string(), warnings);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, serde_json::to_string_pretty(&baselines)?)?;
+            writeln!(
+                self.stdout(),
+                "Saved baseline of {} warnings for {}",
+                total,
+                group
+            )?;
+            return Ok(0);
+        }
+
+        let baseline = baselines
+            .remove(group)
+            .ok_or(anyhow::anyhow!("No baseline saved for group {}", group))?;
+        let mut increased = false;
+        for (name, count) in &warnings {
+            let previous = baseline.get(name).copied().unwrap_or(0);
+            if *count > previous {
+                increased = true;
+                writeln!(
+                    self.stdout(),
+                    "  {} {}",
+                    name,
+                    format!("{} warnings, up from {}", count, previous).red()
+                )?;
+            } else if *count < previous {
+                writeln!(
+                    self.stdout(),
+                    "  {} {}",
+                    name,
+                    format!("{} warnings, down from {}", count, previous).green()
+                )?;
+            }
+        }
+        for (name, previous) in &baseline {
+            if !warnings.contains_key(name) && *previous > 0 {
+                writeln!(
+                    self.stdout(),
+                    "  {} {}",
+                    name,
+                    format!("0 warnings, down from {}", previous).green()
+                )?;
+            }
+        }
+
+        if increased {
+            writeln!(
+                self.stdout(),
+                "{}",
+                format!("Warnings in {} increased", group).red()
+            )?;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+
+    // Runs the command for a single crate, returning its exit code and whether it timed out
+    fn run_crate_command(&self, cmd: &mut process::Command) -> Result<(i32, bool)> {
+        let capture_output = self.execution.summary_only;
+        if capture_output || self.report_file.is_some() {
+            self.pipe_output(cmd);
+        }
+        self.report_line(&format!("Running command: {:?}", cmd))?;
+        // Put cargo in its own process group so that on timeout we can
+        // kill the compilers and test binaries it spawned along with it
+        #[cfg(unix)]
+        if self.execution.timeout.is_some() {
+            std::os::unix::process::CommandExt::process_group(cmd, 0);
+            catch_interrupts();
+        }
+
+        let mut child = cmd.spawn()?;
+        let tee_threads = if capture_output {
+            Vec::new()
+        } else {
+            self.tee_output(&mut child)
+        };
+        let read_pipe = |pipe: Option<Box<dyn Read + Send>>| {
+            pipe.map(|mut pipe| {
+                thread::spawn(move || {
+                    let mut buffer = Vec::new();
+                    pipe.read_to_end(&mut buffer).map(|_| buffer)
+                })
+            })
+        };
+        let stdout = read_pipe(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+        let stderr = read_pipe(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+        let status = match self.execution.timeout {
+            Some(timeout) => wait_with_timeout(&mut child, Duration::from_secs(timeout))?,
+            None => Some(child.wait()?),
+        };
+        let (code, timed_out) = match status {
+            Some(status) => (exit_code(status), false),
+            None => (TIMEOUT_EXIT_CODE, true),
+        };
+
+        for thread in tee_threads {
+            thread.join().expect("output reader panicked")?;
+        }
+        if let (Some(stdout), Some(stderr)) = (stdout, stderr) {
+            let stdout = stdout.join().expect("stdout reader panicked")?;
+            let stderr = stderr.join().expect("stderr reader panicked")?;
+            if code != 0 {
+                self.stdout().write_all(&stdout)?;
+                self.stderr().write_all(&stderr)?;
+            } else {
+                // The output of passing crates is hidden, but still goes in the report.
+                // It's written at once so that parallel crates don't interleave
+                self.report().write_all(&[stdout, stderr].concat())?;
+            }
+        }
+
+        Ok((code, timed_out))
+    }
+
+    fn select_crates(
+        &self,
+        selection: &Selection,
+        only_run_top_level: bool,
+    ) -> Result<Vec<&Package>> {
+        let groups = self.selected_groups(selection)?;
+        let missing: Vec<&str> = groups
+            .iter()
+            .filter(|group| self.group_patterns(group).is_err())
+            .map(String::as_str)
+            .collect();
+        match missing.as_slice() {
+            [] => {}
+            [group] => return Err(anyhow::anyhow!("Group {} not found", group)),
+            groups => return Err(anyhow::anyhow!("Groups {} not found", groups.join(", "))),
+        }
+
+        // The union of the groups, in the order the crates first appear
+        let mut members: Vec<&Package> = Vec::new();
+        for group in &groups {
+            for package in self.get_group_crates(group)? {
+                if !members.iter().any(|member| member.id == package.id) {
+                    members.push(package);
+                }
+            }
+        }
+
+        if !selection.exclude_group.is_empty() {
+            let mut excluded = HashSet::new();
+            for group in &selection.exclude_group {
+                for package in self.get_group_crates(group)? {
+                    excluded.insert(&package.id);
+                }
+            }
+
+            let selected_count = members.len();
+            let before = members.clone();
+            members.retain(|package| !excluded.contains(&package.id));
+            self.explain_removed("Removed by --exclude-group", &before, &members)?;
+            writeln!(
+                self.stderr(),
+                "{}",
+                format!(
+                    "Excluded {} crates in {}",
+                    selected_count - members.len(),
+                    selection.exclude_group.join(", ")
+                )
+                .dimmed()
+            )?;
+        }
+
+        if !selection.exclude.is_empty() {
+            let mut matchers = Vec::new();
+            for glob in &selection.exclude {
+                matchers.push(Glob::new(glob)?.compile_matcher());
+            }
+            let before = members.clone();
+            members.retain(|package| {
+                !matchers
+                    .iter()
+                    .any(|matcher| matcher.is_match(package.name.as_str()))
+            });
+            self.explain_removed("Removed by --exclude", &before, &members)?;
+        }
+
+        if members.is_empty()
+            && !(selection.exclude.is_empty() && selection.exclude_group.is_empty())
+        {
+            return Err(anyhow::anyhow!(
+                "No crates left in {} after exclusions",
+                selection.name()
+            ));
+        }
+        if members.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No crates left in {} to run on",
+                selection.name()
+            ));
+        }
+
+        // Names can't tell apart crates that share a name or different versions of a crate,
+        // so the resolved graph is used unless declared names are asked for
+        let dedup_strategy = selection.dedup_strategy.unwrap_or(if only_run_top_level {
+            DedupStrategy::ResolveGraph
+        } else {
+            DedupStrategy::None
+        });
+        let before = members.clone();
+        match dedup_strategy {
+            DedupStrategy::None => {}
+            DedupStrategy::DeclaredNames => members = remove_dependencies(members),
+            DedupStrategy::ResolveGraph => members = self.remove_resolved_dependencies(members)?,
+        }
+        if !matches!(dedup_strategy, DedupStrategy::None) {
+            self.explain_removed(
+                &format!(
+                    "Removed as dependencies of other crates ({})",
+                    dedup_strategy
+                        .to_possible_value()
+                        .expect("no skipped variants")
+                        .get_name()
+                ),
+                &before,
+                &members,
+            )?;
+        }
+
+        if let Some(git_ref) = &selection.exclude_changed {
+            let changed = self.get_changed_packages(git_ref)?;
+            let before = members.clone();
+            members.retain(|package| !changed.contains(&package.id));
+            self.explain_removed(
+                &format!("Removed as changed since {}", git_ref),
+                &before,
+                &members,
+            )?;
+            if members.is_empty() {
+                writeln!(
+                    self.stderr(),
+                    "{}",
+                    format!(
+                        "Warning: every crate in {} has changed since {}",
+                        selection.name(),
+                        git_ref
+                    )
+                    .yellow()
+                )?;
+            }
+        }
+
+        if let Some(partition) = selection.partition {
+            let before = members.clone();
+            members = partition.select(members);
+            self.explain_removed(
+                &format!(
+                    "Removed by partition {}/{}",
+                    partition.index, partition.total
+                ),
+                &before,
+                &members,
+            )?;
+            writeln!(
+                self.stderr(),
+                "{}",
+                format!(
+                    "Partition {}/{}: {}",
+                    partition.index,
+                    partition.total,
+                    members
+                        .iter()
+                        .map(|member| member.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+                .dimmed()
+            )?;
+        }
+
+        // Per-crate runs go through the crates in dependency order
+        self.sort_topologically(members)
+    }
+
+    // The groups the command runs on that aren't defined in the workspace
+    fn missing_groups(&self, command: &Command) -> Vec<String> {
+        let groups = match command {
+            Command::Test { selection, .. }
+            | Command::Build { selection, .. }
+            | Command::Check { selection, .. }
+            | Command::Clippy { selection, .. }
+            | Command::Fmt { selection, .. }
+            | Command::Doc { selection, .. }
+            | Command::Publish { selection, .. }
+            | Command::Run { selection, .. }
+            | Command::Env { selection, .. } => self
+                .selected_groups(selection)
+                .unwrap_or_else(|_| vec![DEFAULT_GROUP.to_string()]),
+            Command::List {
+                group: Some(group), ..
+            } if self.listed_group_names(Some(group)).is_err() => return vec![group.clone()],
+            Command::Members { group, .. }
+            | Command::Inheritance { group }
+            | Command::Tree { group }
+            | Command::Exec { group, .. }
+            | Command::Watch { group, .. } => vec![group.clone()],
+            Command::DiffDeps { first, second } => vec![first.clone(), second.clone()],
+            Command::Rename { old, .. } => vec![old.clone()],
+            _ => Vec::new(),
+        };
+        groups
+            .into_iter()
+            .filter(|group| self.group_patterns(group).is_err())
+            .collect()
+    }
+
+    // The names of the groups a selection runs on
+    fn selected_groups(&self, selection: &Selection) -> Result<Vec<String>> {
+        let groups = &self.cargo_toml.workspace.metadata.groups;
+        if selection.all_groups {
+            let mut groups: Vec<String> = groups.keys().cloned().collect();
+            groups.sort();
+            return Ok(groups);
+        }
+        if !selection.groups.is_empty() {
+            return Ok(selection.groups.clone());
+        }
+
+        if !groups.contains_key(DEFAULT_GROUP) {
+            return Err(anyhow::anyhow!(
+                "No group given and no `{}` group is defined. Pass a group, or define a \
+                 `{}` group to run on when none is given",
+                DEFAULT_GROUP,
+                DEFAULT_GROUP
+            ));
+        }
+        Ok(vec![DEFAULT_GROUP.to_string()])
+    }
+
+    // Prints what each pattern of the selected groups matches, for --explain-selection
+    fn explain_patterns(&self, selection: &Selection) -> Result<()> {
+        writeln!(
+            self.stderr(),
+            "{}",
+            format!("Selection for {}:", selection.name()).bold()
+        )?;
+        for group in &self.selected_groups(selection)? {
+            writeln!(self.stderr(), "  Group {}:", group)?;
+            for pattern in self.expand_group(group)? {
+                // Matching the pattern on its own shows what it adds, or removes if negated
+                let (matched, _) = self
+                    .match_patterns(&[pattern.strip_prefix('!').unwrap_or(&pattern).to_string()])?;
+                let names = if matched.is_empty() {
+                    "no crates".dimmed().to_string()
+                } else {
+                    package_names(&matched)
+                };
+                let verb = if pattern.starts_with('!') {
+                    "excludes"
+                } else {
+                    "matches"
+                };
+                writeln!(self.stderr(), "    {} {} {}", pattern, verb, names)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Prints the crates a selection step removed, for --explain-selection
+    fn explain_removed(&self, step: &str, before: &[&Package], after: &[&Package]) -> Result<()> {
+        if !self.execution.explain_selection {
+            return Ok(());
+        }
+
+        let removed: Vec<&Package> = before
+            .iter()
+            .copied()
+            .filter(|package| !after.iter().any(|kept| kept.id == package.id))
+            .collect();
+        let names = if removed.is_empty() {
+            "none".dimmed().to_string()
+        } else {
+            package_names(&removed)
+        };
+        writeln!(self.stderr(), "  {}: {}", step, names)?;
+
+        Ok(())
+    }
+
+    fn group_patterns(&self, group: &str) -> Result<&[String]> {
+        self.cargo_toml
+            .workspace
+            .metadata
+            .groups
+            .get(group)
+            .map(GroupDefinition::patterns)
+            .ok_or(anyhow::anyhow!("Group {} not found", group))
+    }
+
+    // The resolved dependency graph of the workspace
+    fn resolve(&self) -> Result<&Resolve> {
+        self.metadata.resolve.as_ref().ok_or(anyhow::anyhow!(
+            "cargo metadata did not resolve dependencies"
+        ))
+    }
+
+    // Sets the environment variables of the groups on the command. With several
+    // groups, the later ones win
+    fn add_group_env(&self, cmd: &mut process::Command, groups: &[String]) {
+        let definitions = &self.cargo_toml.workspace.metadata.groups;
+        for group in groups {
+            if let Some(env) = definitions.get(group).and_then(GroupDefinition::env) {
+                cmd.envs(env);
+            }
+        }
+    }
+
+    // Directory for the state cargo-groups keeps between runs
+    fn state_dir(&self) -> PathBuf {
+        self.metadata
+            .target_directory
+            .join("cargo-groups")
+            .into_std_path_buf()
+    }
+
+    fn save_last_command(&self, argv: &[String]) -> Result<()> {
+        let last_command = LastCommand {
+            cwd: current_dir()?,
+            args: argv.to_vec(),
+        };
+        let state_dir = self.state_dir();
+        fs::create_dir_all(&state_dir)?;
+        fs::write(
+            state_dir.join("last-command.json"),
+            serde_json::to_string(&last_command)?,
+        )?;
+
+        Ok(())
+    }
+
+    fn load_last_command(&self) -> Result<LastCommand> {
+        let contents = fs::read_to_string(self.state_dir().join("last-command.json"))
+            .map_err(|_| anyhow::anyhow!("No command to repeat in this workspace"))?;
+
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    // Cargo sets CARGO to itself when running a subcommand, which is the cargo of the
+    // toolchain we were run with. The one on the PATH may belong to another toolchain
+    fn cargo_command(&self) -> Result<process::Command> {
+        match env::var_os("CARGO") {
+            Some(cargo) => Ok(self.command_for_cargo(cargo.into())),
+            None => Ok(self.command_for_cargo(which("cargo")?)),
+        }
+    }
+
+    // A cargo command with the given arguments. Arguments starting with a toolchain like
+    // `+nightly` need rustup's cargo proxy, which CARGO doesn't point to
+    fn cargo_command_with_args(&self, args: &[String]) -> Result<process::Command> {
+        let mut cmd = match args.first() {
+            Some(arg) if arg.starts_with('+') => self.command_for_cargo(which("cargo")?),
+            _ => self.cargo_command()?,
+        };
+        cmd.args(args);
+        Ok(cmd)
+    }
+
+    fn command_for_cargo(&self, cargo: PathBuf) -> process::Command {
+        let mut cmd = process::Command::new(cargo);
+        cmd.current_dir(&self.cwd);
+        // Otherwise cargo decides for itself
+        match self.color {
+            ColorChoice::Auto => {}
+            ColorChoice::Always => {
+                cmd.env("CARGO_TERM_COLOR", "always");
+            }
+            ColorChoice::Never => {
+                cmd.env("CARGO_TERM_COLOR", "never");
+            }
+        }
+
+        cmd
+    }
+
+    // Creates the directory the artifacts are copied to, unless the command is only printed
+    fn create_artifact_dir(&self, options: &impl Options) -> Result<()> {
+        if let Some(artifact_dir) = options.artifact_dir() {
+            if !self.execution.prints_commands() {
+                fs::create_dir_all(self.cwd.join(artifact_dir))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_command<T>(
+        &self,
+        subcommand: &str,
+        features: &clap_cargo::Features,
+        options: &T,
+        members: &[&Package],
+    ) -> Result<process::Command>
+    where
+        T: Options,
+    {
+        let mut cmd = self.cargo_command()?;
+        cmd.arg(subcommand);
+        if self.execution.counts_warnings() {
+            // Warnings are counted from the JSON diagnostics
+            if colored::control::SHOULD_COLORIZE.should_colorize() {
+                cmd.arg("--message-format=json-diagnostic-rendered-ansi");
+            } else {
+                cmd.arg("--message-format=json");
+            }
+        }
+        if options.takes_features() {
+            add_features(&mut cmd, features);
+        }
+        // Unlike the other options, this one goes before the crates, like in cargo's usage
+        if let Some(artifact_dir) = options.artifact_dir() {
+            cmd.arg("--artifact-dir").arg(artifact_dir);
+        }
+        for member in members {
+            cmd.arg("-p").arg(&member.name);
+        }
+
+        options.add_to_command(&mut cmd);
+
+        Ok(cmd)
+    }
+}
+
+// Decides whether our own output is colored, following the NO_COLOR
+// and CLICOLOR/CLICOLOR_FORCE conventions. CLICOLOR_FORCE wins over NO_COLOR.
+fn should_colorize() -> bool {
+    let is_enabled =
+        |name: &str| env::var_os(name).is_some_and(|value| !value.is_empty() && value != "0");
+    if is_enabled("CLICOLOR_FORCE") {
+        return true;
+    }
+    if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    if env::var_os("CLICOLOR").is_some_and(|value| value == "0") {
+        return false;
+    }
+
+    io::stdout().is_terminal()
+}
+
+// Runs the command on the group, then again each time the files of the group's crates
+// stop changing for `debounce` milliseconds. Only returns on errors
+fn watch(
+    workspace_info: &WorkspaceInfo,
+    group: &str,
+    debounce: u64,
+    command: &[String],
+    argv: &[String],
+) -> Result<i32> {
+    if command[0] == "watch" {
+        return Err(anyhow::anyhow!("watch can't run another watch"));
+    }
+    // The options before `watch` apply to every run, and the group goes after the command
+    let position = argv
+        .iter()
+        .skip(2)
+        .position(|arg| arg == "watch")
+        .expect("argv should contain the watch command")
+        + 2;
+    let mut command_argv = argv[..position].to_vec();
+    command_argv.push(command[0].clone());
+    command_argv.push(group.to_string());
+    command_argv.extend_from_slice(&command[1..]);
+
+    let dirs = workspace_info.watched_dirs(group)?;
+    let debounce = Duration::from_millis(debounce);
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+    loop {
+        // Every run gets a fresh parse of the arguments, since commands are consumed
+        let code = run(Args::parse_from(&command_argv), &command_argv).unwrap_or_else(|err| {
+            eprintln!("{}", format!("Error: {:#}", err).red());
+            1
+        });
+        println!(
+            "{}",
+            format!(
+                "Exited with {}, watching {} crates of {} for changes",
+                code,
+                dirs.len(),
+                group
+            )
+            .dimmed()
+        );
+
+        // Wait for a change, then for the files to settle, since editors and
+        // formatters often write several files at once
+        while !is_change(&dirs, &receiver.recv()??) {}
+        loop {
+            match receiver.recv_timeout(debounce) {
+                Ok(event) => {
+                    event?;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+// Whether the event changed a watched file. Reads are skipped, since the command
+// reads the files itself and would otherwise run again and again
+fn is_change(dirs: &[PathBuf], event: &notify::Event) -> bool {
+    !matches!(event.kind, EventKind::Access(_))
+        && event.paths.iter().any(|path| is_watched_file(dirs, path))
+}
+
+// Whether a change to the path should run the command again. Build output and hidden
+// files like `.git` are skipped, so the command's own output doesn't trigger another run
+fn is_watched_file(dirs: &[PathBuf], path: &Path) -> bool {
+    dirs.iter().any(|dir| {
+        path.strip_prefix(dir).is_ok_and(|relative| {
+            relative.components().all(|component| {
+                let name = component.as_os_str().to_string_lossy();
+                name != "target" && !name.starts_with('.')
+            })
+        })
+    })
+}
+
+// Finds the manifests matching the pattern under root. The pattern can name the
+// manifest or its directory, so `services/*` and `services/*/Cargo.toml` are the same
+fn find_workspace_manifests(root: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = pattern.trim_end_matches('/');
+    let pattern = if pattern == "Cargo.toml" || pattern.ends_with("/Cargo.toml") {
+        pattern.to_string()
+    } else {
+        format!("{}/Cargo.toml", pattern)
+    };
+    let matcher = GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+
+    let mut manifests = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if entry.file_type()?.is_dir() {
+                // Build output and hidden directories don't contain workspaces
+                if name != "target" && !name.to_string_lossy().starts_with('.') {
+                    dirs.push(entry.path());
+                }
+            } else if name == "Cargo.toml" {
+                let path = entry.path();
+                let relative = path.strip_prefix(root).expect("entry should be under root");
+                if matcher.is_match(relative) {
+                    manifests.push(path);
+                }
+            }
+        }
+    }
+    manifests.sort();
+
+    Ok(manifests)
+}
+
+// Runs the command once in each workspace matched by --workspaces-glob, continuing
+// after failures. Workspaces that don't define the groups the command runs on are
+// skipped. Returns the first non-zero exit code
+fn run_in_workspaces(root: &Path, pattern: &str, argv: &[String]) -> Result<i32> {
+    let manifests = find_workspace_manifests(root, pattern)?;
+    if manifests.is_empty() {
+        return Err(anyhow::anyhow!("No workspaces match {}", pattern));
+    }
+
+    let mut exit_code = 0;
+    let mut ran = false;
+    for manifest in manifests {
+        let workspace_dir = manifest.parent().expect("manifest should have a parent");
+        println!(
+            "{}",
+            format!("Workspace {}", relative_path(workspace_dir, root).display()).bold()
+        );
+        // Every workspace gets a fresh parse of the arguments, since commands are consumed
+        let mut args = Args::parse_from(argv);
+        args.workspaces_glob = None;
+        args.cwd = Some(workspace_dir.to_path_buf());
+        args.manifest.manifest_path = Some(manifest.clone());
+        let command = args.command.take().expect("the command was parsed before");
+        let result =
+            WorkspaceInfo::from_args(&args, &command.metadata_flags()).and_then(|workspace_info| {
+                let missing = workspace_info.missing_groups(&command);
+                if !missing.is_empty() {
+                    println!(
+                        "{}",
+                        format!("Skipped, since it has no group {}", missing.join(", ")).dimmed()
+                    );
+                    return Ok(None);
+                }
+                run_command(&args, command, workspace_info, argv).map(Some)
+            });
+        let code = match result {
+            Ok(Some(code)) => code,
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("{}", format!("Error: {:#}", err).red());
+                1
+            }
+        };
+        ran = true;
+        if exit_code == 0 {
+            exit_code = code;
+        }
+    }
+    if !ran {
+        return Err(anyhow::anyhow!(
+            "None of the workspaces matching {} define the groups to run on",
+            pattern
+        ));
+    }
+
+    Ok(exit_code)
+}
+
+fn print_commands(workspace_info: Option<&WorkspaceInfo>) -> Result<()> {
+    println!("{}", "Built-in commands:".bold());
+    let commands = Command::command();
+    let width = commands
+        .get_subcommands()
+        .map(|command| command.get_name().len())
+        .max()
+        .unwrap_or(0);
+    for command in commands.get_subcommands() {
+        let about = command
+            .get_about()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        println!("  {:width$}  {}", command.get_name(), about, width = width);
+    }
+
+    let Some(workspace_info) = workspace_info else {
+        return Ok(());
+    };
+    let custom_commands: BTreeMap<_, _> = workspace_info
+        .cargo_toml
+        .workspace
+        .metadata
+        .groups_commands
+        .iter()
+        .collect();
+    if custom_commands.is_empty() {
+        return Ok(());
+    }
+
+    // `cargo --list` includes cargo's own commands, aliases and the installed external ones
+    let output = workspace_info.cargo_command()?.arg("--list").output()?;
+    let available: HashSet<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(ToString::to_string)
+        .collect();
+    println!();
+    println!("{}", "Custom commands:".bold());
+    let width = custom_commands
+        .keys()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, template) in custom_commands {
+        // The cargo command is the first argument that isn't a toolchain, like `+nightly`
+        let cargo_command = template
+            .split_whitespace()
+            .find(|arg| !arg.starts_with('+'));
+        let status = match cargo_command {
+            Some(command) if available.contains(command) => "available".green(),
+            Some(command) => format!("cargo {} not found", command).red(),
+            None => "no cargo command".red(),
+        };
+        println!(
+            "  {:width$}  {}  ({})",
+            name,
+            template,
+            status,
+            width = width
+        );
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let argv: Vec<String> = env::args().collect();
+    let args = Args::parse_from(&argv);
+    colored::control::set_override(match args.color {
+        ColorChoice::Auto => should_colorize(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    });
+    match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::from_default_env())
+            .init(),
+    }
+
+    if args.json_schema {
+        let schema = schemars::schema_for!(RootCargoToml);
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+
+    process::exit(run(args, &argv)?);
+}
+
+// Shells complete `cargo` with cargo's own script, so this completes the binary,
+// which is run as `cargo-groups groups <COMMAND>`
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn Write) {
+    let mut command = Args::command()
+        .bin_name("cargo-groups")
+        .mut_arg("_subcommand_name", |arg| {
+            arg.value_name("groups").value_parser(["groups"])
+        });
+    clap_complete::generate(shell, &mut command, "cargo-groups", out);
+}
+
+// argv is the command line the arguments were parsed from, which repeat runs again
+fn run(mut args: Args, argv: &[String]) -> Result<i32> {
+    if let Some(Command::Completions { shell }) = &args.command {
+        write_completions(*shell, &mut io::stdout());
+        return Ok(0);
+    }
+    if args.list_commands {
+        // Custom commands are only known inside a workspace
+        print_commands(WorkspaceInfo::from_args(&args, &[]).ok().as_ref())?;
+        return Ok(0);
+    }
+    let Some(command) = args.command.take() else {
+        Args::command()
+            .error(ErrorKind::MissingSubcommand, "a command is required")
+            .exit();
+    };
+    if let Some(pattern) = &args.workspaces_glob {
+        let root = args.cwd.clone().unwrap_or(current_dir()?);
+        return run_in_workspaces(&root, pattern, argv);
+    }
+    let workspace_info = WorkspaceInfo::from_args(&args, &command.metadata_flags())?;
+    run_command(&args, command, workspace_info, argv)
+}
+
+fn run_command(
+    args: &Args,
+    command: Command,
+    mut workspace_info: WorkspaceInfo,
+    argv: &[String],
+) -> Result<i32> {
+    if let Command::Repeat = command {
+        let last_command = workspace_info.load_last_command()?;
+        println!(
+            "{}",
+            format!("Repeating: cargo {}", last_command.args[1..].join(" ")).dimmed()
+        );
+        env::set_current_dir(&last_command.cwd)?;
+        return run(Args::parse_from(&last_command.args), &last_command.args);
+    }
+    if let Command::Watch {
+        group,
+        debounce,
+        command,
+    } = &command
+    {
+        return watch(&workspace_info, group, *debounce, command, argv);
+    }
+    if args.allow_no_groups
+        && workspace_info
+            .cargo_toml
+            .workspace
+            .metadata
+            .groups
+            .is_empty()
+        && !matches!(
+            command,
+            Command::List { group: None, .. }
+                | Command::VerifyMembers
+                | Command::Orphans { .. }
+                | Command::Touched { .. }
+        )
+    {
+        println!("No groups found");
+        return Ok(0);
+    }
+    if matches!(
+        command,
+        Command::Test { .. }
+            | Command::Build { .. }
+            | Command::Check { .. }
+            | Command::Clippy { .. }
+            | Command::Doc { .. }
+            | Command::Fmt { .. }
+            | Command::Run { .. }
+            | Command::Publish { .. }
+            | Command::Exec { .. }
+            | Command::Custom(_)
+    ) {
+        workspace_info.save_last_command(argv)?;
+    }
+
+    Ok(match command {
+        Command::Test {
+            selection,
+            features,
+            mut options,
+        } => {
+            options.specific.choose_shuffle_seed();
+            workspace_info.execute_on_group("test", &selection, features, options, false)?
+        }
+        Command::Build {
+            selection,
+            features,
+            options,
+        } => workspace_info.execute_on_group("build", &selection, features, options, true)?,
         Command::Check {
-            group,
+            selection,
             features,
             options,
-        } => workspace_info.execute_on_group("check", &group, features, options, true)?,
+        } => workspace_info.execute_on_group("check", &selection, features, options, true)?,
         Command::Clippy {
+            selection,
+            features,
+            options,
+        } => workspace_info.execute_on_group("clippy", &selection, features, options, true)?,
+        Command::Doc {
+            selection,
+            features,
+            options,
+        } => workspace_info.execute_on_group("doc", &selection, features, options, true)?,
+        Command::Fmt { selection, options } => workspace_info.execute_on_group(
+            "fmt",
+            &selection,
+            clap_cargo::Features::default(),
+            options,
+            false,
+        )?,
+        Command::Run {
+            selection,
+            features,
+            options,
+        } => workspace_info.execute_on_group("run", &selection, features, options, false)?,
+        Command::Publish {
+            selection,
+            features,
+            mut options,
+        } => {
+            // `cargo publish --dry-run` checks the crates without uploading them,
+            // which is more useful than printing the commands
+            options.dry_run = workspace_info.execution.dry_run;
+            workspace_info.execution.dry_run = false;
+            workspace_info.execute_on_group("publish", &selection, features, options, false)?
+        }
+        Command::List {
+            group, json: true, ..
+        } => {
+            workspace_info.print_groups_json(group.as_deref())?;
+            0
+        }
+        Command::List {
+            group,
+            descriptions_only: true,
+            ..
+        } => {
+            workspace_info.print_group_descriptions(&mut io::stdout().lock(), group.as_deref())?;
+            0
+        }
+        Command::List {
             group,
+            group_separator,
+            ..
+        } => {
+            workspace_info.print_groups(
+                &mut io::stdout().lock(),
+                group.as_deref(),
+                group_separator.as_deref(),
+            )?;
+            0
+        }
+        Command::Env {
+            selection,
+            features,
+            options,
+        } => {
+            workspace_info.print_env(&selection, features, options)?;
+            0
+        }
+        Command::Members { group, print0 } => {
+            workspace_info.print_manifest_paths(&group, print0)?;
+            0
+        }
+        Command::Inheritance { group } => {
+            workspace_info.print_inheritance(&group)?;
+            0
+        }
+        Command::Tree { group } => {
+            workspace_info.print_tree(&mut io::stdout().lock(), &group)?;
+            0
+        }
+        Command::DiffDeps { first, second } => {
+            workspace_info.print_dependency_diff(&first, &second)?;
+            0
+        }
+        Command::VerifyMembers => workspace_info.verify_members()?,
+        Command::Orphans { json } => {
+            workspace_info.print_orphans(json)?;
+            0
+        }
+        Command::Touched { path } => {
+            workspace_info.print_touched(&path)?;
+            0
+        }
+        Command::Rename { old, new } => {
+            workspace_info.rename_group(&old, &new)?;
+            0
+        }
+        Command::Exec { group, args } => workspace_info.execute_exec(&group, &args)?,
+        Command::Custom(args) => workspace_info.execute_custom_command(&args)?,
+        Command::Repeat => unreachable!("repeat is handled above"),
+        Command::Watch { .. } => unreachable!("watch is handled above"),
+        Command::Completions { .. } => unreachable!("completions are handled above"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The arguments of a command, without the program
+    fn command_args(cmd: &process::Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name)
+    }
+
+    // The fixture workspace, where a -> b -> c, x -> y and x -> z, e dev-depends on d and f
+    // depends on a `util` crate outside of the workspace. Groups replace the fixture's groups
+    fn workspace(flags: &[&str], groups: &[(&str, &[&str])]) -> WorkspaceInfo {
+        let mut workspace = WorkspaceInfo::from_args(&fixture_args(flags), &[]).unwrap();
+        workspace.cargo_toml.workspace.metadata.groups = groups
+            .iter()
+            .map(|(name, group_patterns)| {
+                (
+                    name.to_string(),
+                    GroupDefinition::Patterns(patterns(group_patterns)),
+                )
+            })
+            .collect();
+        workspace
+    }
+
+    // The arguments of `cargo groups` run on the fixture workspace
+    fn fixture_args(flags: &[&str]) -> Args {
+        let manifest_path = fixture("workspace").join("Cargo.toml");
+        let mut argv = vec![
+            "cargo",
+            "groups",
+            "--manifest-path",
+            manifest_path.to_str().unwrap(),
+        ];
+        argv.extend(flags);
+        Args::parse_from(argv)
+    }
+
+    // Runs a check or publish command on the fixture workspace, like `run` but
+    // without saving it as the last command
+    fn run_group(flags: &[&str], groups: &[(&str, &[&str])]) -> Result<i32> {
+        let workspace = workspace(flags, groups);
+        match fixture_args(flags).command {
+            Some(Command::Check {
+                selection,
+                features,
+                options,
+            }) => workspace.run_on_group("check", &selection, features, options, true),
+            Some(Command::Publish {
+                selection,
+                features,
+                options,
+            }) => workspace.run_on_group("publish", &selection, features, options, false),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    fn package<'a>(workspace: &'a WorkspaceInfo, name: &str) -> &'a Package {
+        workspace
+            .metadata
+            .workspace_packages()
+            .into_iter()
+            .find(|package| package.name == name)
+            .unwrap()
+    }
+
+    // The arguments of the cargo command that a command on the fixture workspace
+    // would run, built from its crates like `run_on_group` does
+    fn planned_args(flags: &[&str], groups: &[(&str, &[&str])]) -> Vec<String> {
+        fn build<T: Options>(
+            workspace: &WorkspaceInfo,
+            subcommand: &str,
+            selection: &Selection,
+            features: clap_cargo::Features,
+            options: T,
+            only_run_top_level: bool,
+        ) -> Vec<String> {
+            let members = workspace
+                .select_crates(selection, only_run_top_level)
+                .unwrap();
+            let cmd = workspace
+                .build_command(subcommand, &features, &options, &members)
+                .unwrap();
+            command_args(&cmd)
+        }
+
+        let workspace = workspace(flags, groups);
+        match fixture_args(flags).command {
+            Some(Command::Test {
+                selection,
+                features,
+                options,
+            }) => build(&workspace, "test", &selection, features, options, false),
+            Some(Command::Build {
+                selection,
+                features,
+                options,
+            }) => build(&workspace, "build", &selection, features, options, true),
+            Some(Command::Clippy {
+                selection,
+                features,
+                options,
+            }) => build(&workspace, "clippy", &selection, features, options, true),
+            Some(Command::Doc {
+                selection,
+                features,
+                options,
+            }) => build(&workspace, "doc", &selection, features, options, true),
+            Some(Command::Fmt { selection, options }) => build(
+                &workspace,
+                "fmt",
+                &selection,
+                clap_cargo::Features::default(),
+                options,
+                false,
+            ),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    // The names of the crates that `cargo groups test` selects on the fixture workspace
+    fn selected(flags: &[&str], groups: &[(&str, &[&str])]) -> Result<Vec<String>> {
+        let workspace = workspace(flags, groups);
+        let Some(Command::Test { selection, .. }) = fixture_args(flags).command else {
+            panic!("expected a test command");
+        };
+        let members = workspace.select_crates(&selection, false)?;
+        Ok(names(&members).into_iter().map(String::from).collect())
+    }
+
+    // An empty directory for a test, with the given files
+    fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            env::temp_dir()
+                .join("cargo-groups-tests")
+                .join(format!("{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(ToString::to_string).collect()
+    }
+
+    fn names<'a>(packages: &[&'a Package]) -> Vec<&'a str> {
+        packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect()
+    }
+
+    // A cargo command with the command options added, and the rustflags variables
+    // unset so they don't leak in from the environment
+    fn option_command<T>(flags: &[&str]) -> process::Command
+    where
+        T: Options + Parser + ClapArgs,
+    {
+        let options = CommandOptions::<T>::parse_from(["cargo-groups"].iter().chain(flags.iter()));
+        let mut cmd = process::Command::new("cargo");
+        cmd.env_remove("CARGO_ENCODED_RUSTFLAGS")
+            .env_remove("RUSTFLAGS");
+        options.add_to_command(&mut cmd);
+        cmd
+    }
+
+    // The arguments that the command options add to a cargo command
+    fn option_args<T>(flags: &[&str]) -> Vec<String>
+    where
+        T: Options + Parser + ClapArgs,
+    {
+        command_args(&option_command::<T>(flags))
+    }
+
+    // The value the command sets an environment variable to
+    fn command_env(cmd: &process::Command, key: &str) -> Option<String> {
+        cmd.get_envs()
+            .find(|(name, _)| *name == key)
+            .and_then(|(_, value)| value)
+            .map(|value| value.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn add_features_passes_features_as_one_value() {
+        let mut cmd = process::Command::new("cargo");
+        let mut features = clap_cargo::Features::default();
+        features.features = vec!["a".to_string(), "b".to_string()];
+        add_features(&mut cmd, &features);
+
+        assert_eq!(command_args(&cmd), ["--features", "a,b"]);
+    }
+
+    #[test]
+    fn resolved_dedup_keeps_crates_sharing_a_name_with_a_dependency() {
+        // Cargo doesn't allow two members with the same name, so the other `util`
+        // is the path dependency of f outside of the workspace
+        let workspace = workspace(&[], &[("utils", &["pkg:f", "pkg:util"])]);
+        let members = workspace.get_group_crates("utils").unwrap();
+
+        let kept = workspace.remove_resolved_dependencies(members).unwrap();
+        assert_eq!(names(&kept), ["f", "util"]);
+    }
+
+    #[test]
+    fn resolved_dedup_keeps_dev_dependencies() {
+        let workspace = workspace(&[], &[("pairs", &["pkg:a", "pkg:b", "pkg:d", "pkg:e"])]);
+        let members = workspace.get_group_crates("pairs").unwrap();
+
+        let kept = workspace.remove_resolved_dependencies(members).unwrap();
+        assert_eq!(names(&kept), ["a", "d", "e"]);
+    }
+
+    #[test]
+    fn group_references_are_expanded_recursively() {
+        let workspace = workspace(
+            &[],
+            &[
+                ("low", &["pkg:c"]),
+                ("mid", &["pkg:b", "group:low"]),
+                ("top", &["pkg:a", "group:mid"]),
+            ],
+        );
+
+        assert_eq!(
+            names(&workspace.get_group_crates("top").unwrap()),
+            ["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn crate_reached_through_two_groups_is_selected_once() {
+        let workspace = workspace(
+            &[],
+            &[
+                ("core", &["pkg:a", "pkg:b"]),
+                ("lower", &["pkg:b", "pkg:c"]),
+                ("both", &["group:core", "group:lower"]),
+            ],
+        );
+
+        assert_eq!(
+            names(&workspace.get_group_crates("both").unwrap()),
+            ["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn cyclic_group_references_are_an_error() {
+        let workspace = workspace(&[], &[("a", &["group:b"]), ("b", &["pkg:c", "group:a"])]);
+
+        let err = workspace.get_group_crates("a").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cyclic group reference detected: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn excluded_group_references_are_an_error() {
+        // Groups from the workspace helper skip the checks done when loading them
+        let workspace = workspace(&[], &[("a", &["members", "!group:b"]), ("b", &["pkg:c"])]);
+
+        let err = workspace.get_group_crates("a").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Group a has the pattern '!group:b', but groups can't be excluded"));
+    }
+
+    #[test]
+    fn generated_groups_parse_errors_name_the_file_read() {
+        let dir = temp_dir(
+            "generated-groups",
+            &[
+                ("Cargo.toml", "[workspace]\n"),
+                ("other.toml", "groups = 1\n"),
+            ],
+        );
+        let other = dir.join("other.toml");
+
+        let err = RootCargoToml::load(&dir.join("Cargo.toml"), Some(&other))
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Failed to parse {}:", other.display())));
+    }
+
+    #[test]
+    fn ansi_escapes_are_stripped_across_chunks() {
+        let mut stripper = AnsiStripper::default();
+        let mut stripped = stripper.strip(b"\x1b[1;31merror\x1b[");
+        stripped.extend(stripper.strip(b"0m: oops"));
+        assert_eq!(String::from_utf8(stripped).unwrap(), "error: oops");
+    }
+
+    #[test]
+    fn report_file_gets_messages_without_colors() {
+        let dir = temp_dir("report-file", &[]);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("report.txt");
+        let mut writer = ReportWriter {
+            terminal: Vec::new(),
+            report_file: Some(Arc::new(Mutex::new(fs::File::create(&path).unwrap()))),
+            stripper: AnsiStripper::default(),
+        };
+        writeln!(writer, "\x1b[2mSkipping 1 unchanged crates: a\x1b[0m").unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.terminal).unwrap(),
+            "\x1b[2mSkipping 1 unchanged crates: a\x1b[0m\n"
+        );
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "Skipping 1 unchanged crates: a\n"
+        );
+    }
+
+    #[test]
+    fn completions_complete_the_groups_argument_and_commands() {
+        let mut script = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+
+        assert!(!script.is_empty());
+        let words: HashSet<&str> = script
+            .split(|c: char| c.is_whitespace() || c == '"')
+            .collect();
+        // Cargo runs the binary with `groups` before the command
+        for word in ["groups", "test", "build", "check", "list"] {
+            assert!(words.contains(word), "missing {}", word);
+        }
+    }
+
+    #[test]
+    fn rustflags_extend_the_ones_in_the_environment() {
+        let mut cmd = process::Command::new("cargo");
+        cmd.env_remove("CARGO_ENCODED_RUSTFLAGS")
+            .env_remove("RUSTFLAGS");
+        add_rustflags(&mut cmd, &["-C", "target-cpu=native"]);
+        add_rustflags(&mut cmd, &["--diagnostic-width=80"]);
+        assert!(command_args(&cmd).is_empty());
+        assert_eq!(
+            command_env(&cmd, "RUSTFLAGS").as_deref(),
+            Some("-C target-cpu=native --diagnostic-width=80")
+        );
+
+        let mut cmd = process::Command::new("cargo");
+        cmd.env_remove("CARGO_ENCODED_RUSTFLAGS")
+            .env("RUSTFLAGS", "-D warnings");
+        add_rustflags(&mut cmd, &["--diagnostic-width=80"]);
+        assert_eq!(
+            command_env(&cmd, "RUSTFLAGS").as_deref(),
+            Some("-D warnings --diagnostic-width=80")
+        );
+
+        let mut cmd = process::Command::new("cargo");
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", "-D\x1fwarnings")
+            .env("RUSTFLAGS", "-D warnings");
+        add_rustflags(&mut cmd, &["--diagnostic-width=80"]);
+        assert_eq!(
+            command_env(&cmd, "CARGO_ENCODED_RUSTFLAGS").as_deref(),
+            Some("-D\x1fwarnings\x1f--diagnostic-width=80")
+        );
+        assert_eq!(
+            command_env(&cmd, "RUSTFLAGS").as_deref(),
+            Some("-D warnings")
+        );
+    }
+
+    #[test]
+    fn only_changes_to_the_crates_sources_are_watched() {
+        let crates = fixture("workspace").join("crates");
+        let dirs = [crates.join("a"), crates.join("b")];
+
+        assert!(is_watched_file(&dirs, &crates.join("a/src/lib.rs")));
+        assert!(is_watched_file(&dirs, &crates.join("b/Cargo.toml")));
+        assert!(!is_watched_file(&dirs, &crates.join("c/src/lib.rs")));
+        assert!(!is_watched_file(&dirs, &crates.join("a/target/debug/a")));
+        assert!(!is_watched_file(&dirs, &crates.join("a/.git/index")));
+
+        // Reading a file isn't a change, since the command reads them all
+        let lib = crates.join("a/src/lib.rs");
+        let modify = notify::Event::new(EventKind::Modify(notify::event::ModifyKind::Any));
+        let access = notify::Event::new(EventKind::Access(notify::event::AccessKind::Any));
+        assert!(is_change(&dirs, &modify.add_path(lib.clone())));
+        assert!(!is_change(&dirs, &access.add_path(lib)));
+    }
+
+    #[test]
+    fn path_globs_ignore_leading_and_trailing_slashes() {
+        for glob in ["crates/a", "/crates/a", "crates/a/", "/crates/a/"] {
+            let matcher = path_glob(glob, false).unwrap().compile_matcher();
+            assert!(matcher.is_match("crates/a"), "{} should match", glob);
+            assert!(!matcher.is_match("crates/ab"), "{} shouldn't match", glob);
+        }
+
+        let workspace = workspace(&[], &[("anchored", &["path:/crates/a", "path:/crates/b/"])]);
+        let members = workspace.get_group_crates("anchored").unwrap();
+        assert_eq!(names(&members), ["a", "b"]);
+    }
+
+    #[test]
+    fn root_package_groups_are_merged_with_the_workspace_groups() {
+        let manifest_path = fixture("root-package").join("Cargo.toml");
+        let args = Args::parse_from([
+            "cargo",
+            "groups",
+            "--manifest-path",
+            manifest_path.to_str().unwrap(),
+        ]);
+        let workspace = WorkspaceInfo::from_args(&args, &[]).unwrap();
+
+        assert_eq!(
+            names(&workspace.get_group_crates("root").unwrap()),
+            ["root"]
+        );
+        assert_eq!(
+            names(&workspace.get_group_crates("member").unwrap()),
+            ["member"]
+        );
+        // The workspace's definition of a group takes precedence over the package's
+        assert_eq!(
+            names(&workspace.get_group_crates("shared").unwrap()),
+            ["member", "root"]
+        );
+    }
+
+    #[test]
+    fn unstable_flags_are_forwarded_in_order() {
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&[
+                "-Z",
+                "build-std",
+                "-Zbuild-std-features=panic_immediate_abort"
+            ]),
+            [
+                "-Z",
+                "build-std",
+                "-Z",
+                "build-std-features=panic_immediate_abort"
+            ]
+        );
+        // Flags implied by our options aren't passed twice
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&[
+                "--unstable-options",
+                "-Z",
+                "unstable-options"
+            ]),
+            ["-Z", "unstable-options"]
+        );
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&["--unit-graph", "-Z", "build-std"]),
+            ["--unit-graph", "-Z", "unstable-options", "-Z", "build-std"]
+        );
+    }
+
+    #[test]
+    fn rename_group_renames_references_and_defaults() {
+        let mut document = r#"
+[groups]
+core = ["pkg:a"] # the core
+all = ["group:core", "pkg:b"]
+
+[groups-defaults.core]
+release = true
+"#
+        .parse::<toml_edit::Document>()
+        .unwrap();
+        assert!(rename_group_in_metadata(
+            document.as_table_mut(),
+            "core",
+            "main"
+        ));
+
+        assert_eq!(
+            document.to_string(),
+            r#"
+[groups]
+main = ["pkg:a"] # the core
+all = ["group:main", "pkg:b"]
+
+[groups-defaults.main]
+release = true
+"#
+        );
+    }
+
+    #[test]
+    fn rename_group_only_writes_the_files_defining_it() {
+        const MANIFEST: &str =
+            "[package]\nname = \"renamed\"\nversion = \"0.1.0\"\nedition = \"2021\"\n";
+        let dir = temp_dir(
+            "rename-group",
+            &[
+                ("Cargo.toml", MANIFEST),
+                ("src/lib.rs", ""),
+                ("cargo-groups.toml", "[groups]\ncore = [\"members\"]\n"),
+            ],
+        );
+        let cargo_toml = dir.join("Cargo.toml");
+        let old_time = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&cargo_toml)
+            .unwrap()
+            .set_modified(old_time)
+            .unwrap();
+        let args = Args::parse_from([
+            "cargo",
+            "groups",
+            "--manifest-path",
+            cargo_toml.to_str().unwrap(),
+        ]);
+        let workspace = WorkspaceInfo::from_args(&args, &[]).unwrap();
+
+        workspace.rename_group("core", "main").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("cargo-groups.toml")).unwrap(),
+            "[groups]\nmain = [\"members\"]\n"
+        );
+        assert_eq!(fs::read_to_string(&cargo_toml).unwrap(), MANIFEST);
+        assert_eq!(
+            fs::metadata(&cargo_toml).unwrap().modified().unwrap(),
+            old_time
+        );
+    }
+
+    #[test]
+    fn groups_outside_the_config_files_cant_be_renamed() {
+        let workspace = workspace(&[], &[("chain", &["pkg:a"])]);
+
+        assert_eq!(
+            workspace
+                .rename_group("chain", "links")
+                .unwrap_err()
+                .to_string(),
+            "Group chain isn't defined in Cargo.toml or .cargo-groups.toml or \
+             cargo-groups.toml, so it can't be renamed"
+        );
+    }
+
+    #[test]
+    fn defaults_for_unknown_groups_are_an_error() {
+        let dir = temp_dir(
+            "unknown-defaults",
+            &[(
+                "Cargo.toml",
+                "[workspace.metadata.groups]\ncore = [\"pkg:a\"]\n\
+                 [workspace.metadata.groups-defaults.cor]\nrelease = true\n",
+            )],
+        );
+
+        let err = RootCargoToml::load(&dir.join("Cargo.toml"), None)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "groups-defaults has options for group cor, which isn't defined"
+        );
+    }
+
+    #[test]
+    fn per_crate_flags_require_per_crate_mode() {
+        let error = run_group(
+            &["check", "chain", "--from", "b", "--dry-run"],
+            &[("chain", &["pkg:a", "pkg:b", "pkg:c"])],
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "--from requires --per-crate");
+    }
+
+    #[test]
+    fn per_crate_flags_work_with_commands_that_always_run_per_crate() {
+        let groups: &[(&str, &[&str])] = &[("chain", &["pkg:a", "pkg:b", "pkg:c"])];
+        let code = run_group(
+            &[
+                "publish",
+                "chain",
+                "--from",
+                "b",
+                "--continue-on-error",
+                "--dry-run",
+            ],
+            groups,
+        )
+        .unwrap();
+        assert_eq!(code, 0);
+
+        let error =
+            run_group(&["publish", "chain", "--from", "d", "--dry-run"], groups).unwrap_err();
+        assert_eq!(error.to_string(), "Crate d is not in group chain");
+    }
+
+    #[test]
+    fn inherited_fields_are_found_in_every_table() {
+        let manifest: toml::Table = toml::from_str(
+            r#"
+            [package]
+            name = "a"
+            version.workspace = true
+            edition = "2021"
+
+            [lints]
+            workspace = true
+
+            [dependencies]
+            serde = { workspace = true, features = ["derive"] }
+            anyhow = "1"
+
+            [dev-dependencies]
+            local = { path = "../local", workspace = false }
+
+            [target.'cfg(unix)'.build-dependencies]
+            cc.workspace = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_inherited_fields(&manifest),
+            [
+                "package.version",
+                "lints",
+                "dependencies.serde",
+                "target.cfg(unix).build-dependencies.cc"
+            ]
+        );
+    }
+
+    #[test]
+    fn diagnostic_width_is_passed_to_rustc() {
+        let cmd = option_command::<DefaultSpecificOptions>(&["--diagnostic-width", "80"]);
+        assert!(command_args(&cmd).is_empty());
+        assert_eq!(
+            command_env(&cmd, "RUSTFLAGS").as_deref(),
+            Some("--diagnostic-width=80")
+        );
+    }
+
+    #[test]
+    fn command_templates_expand_placeholders() {
+        let workspace = workspace(&[], &[("chain", &["pkg:a", "pkg:b"])]);
+        let members = workspace.get_group_crates("chain").unwrap();
+
+        assert_eq!(
+            expand_command_template("hack check {packages} --each-feature", "chain", &members)
+                .unwrap(),
+            ["hack", "check", "-p", "a", "-p", "b", "--each-feature"]
+        );
+        // Without {packages}, the -p flags go at the end
+        assert_eq!(
+            expand_command_template("doc --target-dir target/{group}", "chain", &members).unwrap(),
+            ["doc", "--target-dir", "target/chain", "-p", "a", "-p", "b"]
+        );
+        let err = expand_command_template("build --out {out}/bin", "chain", &members).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown placeholder {out} in command template `build --out {out}/bin`. Expected {packages} or {group}"
+        );
+    }
+
+    #[test]
+    fn path_patterns_match_the_crate_directory() {
+        let strict = workspace(
+            &["--workspace-relative-globs"],
+            &[("nested", &["path:*/a"])],
+        );
+        assert_eq!(names(&strict.get_group_crates("nested").unwrap()), ["a"]);
+
+        let workspace = workspace(
+            &[],
+            &[("api", &["path:crates/a"]), ("all", &["path:crates/*"])],
+        );
+        assert_eq!(
+            workspace.get_package_path_relative_to_workspace(package(&workspace, "a")),
+            Path::new("crates/a")
+        );
+        assert_eq!(names(&workspace.get_group_crates("api").unwrap()), ["a"]);
+        assert_eq!(workspace.get_group_crates("all").unwrap().len(), 10);
+
+        // With --workspace-relative-globs, `*` doesn't cross directories
+        for (glob, strict, matches) in [
+            ("*", false, true),
+            ("*", true, false),
+            ("**", true, true),
+            ("crates/*", true, true),
+        ] {
+            let matcher = path_glob(glob, strict).unwrap().compile_matcher();
+            assert_eq!(matcher.is_match("crates/a"), matches, "{} {}", glob, strict);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn timed_out_commands_are_killed_with_what_they_spawned() {
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", "sleep 30 & echo $!; wait"])
+            .stdout(process::Stdio::piped());
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let mut child = cmd.spawn().unwrap();
+        let mut line = String::new();
+        io::BufRead::read_line(
+            &mut io::BufReader::new(child.stdout.take().unwrap()),
+            &mut line,
+        )
+        .unwrap();
+        let sleep_pid = line.trim().to_string();
+
+        let started = Instant::now();
+        assert!(wait_with_timeout(&mut child, Duration::from_millis(200))
+            .unwrap()
+            .is_none());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        // The sleep in the background is killed too, though it may not be reaped yet
+        thread::sleep(Duration::from_millis(100));
+        let stat = fs::read_to_string(format!("/proc/{}/stat", sleep_pid)).unwrap_or_default();
+        let state = stat.rsplit(')').next().unwrap_or_default().trim_start();
+        assert!(stat.is_empty() || state.starts_with(['Z', 'X']), "{}", stat);
+    }
+
+    #[test]
+    fn target_applies_to_host_is_an_unstable_flag() {
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&["--target-applies-to-host"]),
+            ["-Z", "target-applies-to-host"]
+        );
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&[
+                "--target-applies-to-host",
+                "-Z",
+                "target-applies-to-host"
+            ]),
+            ["-Z", "target-applies-to-host"]
+        );
+    }
+
+    #[test]
+    fn partitions_split_the_sorted_crates_round_robin() {
+        for (partition, err) in [
+            ("1", "expected <INDEX>/<TOTAL>, e.g. 1/3"),
+            ("a/3", "invalid partition index a"),
+            ("1/b", "invalid partition total b"),
+            ("0/3", "partition index must be between 1 and 3"),
+            ("4/3", "partition index must be between 1 and 3"),
+            ("1/0", "partition index must be between 1 and 0"),
+        ] {
+            assert_eq!(partition.parse::<Partition>().unwrap_err(), err);
+        }
+
+        let workspace = workspace(&[], &[("all", &["members"])]);
+        let members = workspace.get_group_crates("all").unwrap();
+        let partitions: Vec<Vec<&str>> = ["1/3", "2/3", "3/3"]
+            .iter()
+            .map(|partition| {
+                let partition: Partition = partition.parse().unwrap();
+                names(&partition.select(members.clone()))
+            })
+            .collect();
+        assert_eq!(
+            partitions,
+            [
+                vec!["a", "d", "util", "z"],
+                vec!["b", "e", "x"],
+                vec!["c", "f", "y"]
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_interpolate_environment_variables() {
+        // Cargo sets CARGO_PKG_NAME when running the tests
+        assert_eq!(
+            interpolate_env("path:crates/${CARGO_PKG_NAME}-*").unwrap(),
+            format!("path:crates/{}-*", env::var("CARGO_PKG_NAME").unwrap())
+        );
+        assert_eq!(
+            interpolate_env("pkg:${CARGO_GROUPS_TEST_UNSET_SERVICE:-api}-*").unwrap(),
+            "pkg:api-*"
+        );
+        assert_eq!(interpolate_env("pkg:$$a$b").unwrap(), "pkg:$a$b");
+        assert_eq!(
+            interpolate_env("pkg:${CARGO_GROUPS_TEST_UNSET_SERVICE}")
+                .unwrap_err()
+                .to_string(),
+            "Environment variable CARGO_GROUPS_TEST_UNSET_SERVICE used in pattern pkg:${CARGO_GROUPS_TEST_UNSET_SERVICE} is not set"
+        );
+        assert_eq!(
+            interpolate_env("pkg:${SERVICE").unwrap_err().to_string(),
+            "Unclosed ${ in pattern pkg:${SERVICE"
+        );
+    }
+
+    #[test]
+    fn profile_overrides_apply_to_the_active_profile() {
+        assert_eq!(toml_value("3"), "3");
+        assert_eq!(toml_value("false"), "false");
+        assert_eq!(toml_value("s"), r#""s""#);
+        assert_eq!(toml_value("line-tables-only"), r#""line-tables-only""#);
+
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&["--opt-level", "1", "--debug", "false"]),
+            [
+                "--config",
+                "profile.dev.opt-level=1",
+                "--config",
+                "profile.dev.debug=false"
+            ]
+        );
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&["--release", "--opt-level", "z"]),
+            ["--release", "--config", r#"profile.release.opt-level="z""#]
+        );
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&[
+                "--profile",
+                "ci",
+                "--debug",
+                "line-tables-only"
+            ]),
+            [
+                "--profile",
+                "ci",
+                "--config",
+                r#"profile.ci.debug="line-tables-only""#
+            ]
+        );
+    }
+
+    #[test]
+    fn doc_forwards_open_and_no_deps() {
+        // Like check, docs only run on the top-level crates since a depends on b and c
+        assert_eq!(
+            planned_args(
+                &["doc", "--open", "--no-deps", "chain"],
+                &[("chain", &["pkg:a", "pkg:b", "pkg:c"])]
+            ),
+            ["doc", "-p", "a", "--open", "--no-deps"]
+        );
+    }
+
+    #[test]
+    fn fmt_runs_on_every_crate_without_features() {
+        // Dependencies come first, and fmt runs on them too since it isn't top-level only
+        assert_eq!(
+            planned_args(
+                &["fmt", "--check", "chain"],
+                &[("chain", &["pkg:a", "pkg:b"])]
+            ),
+            ["fmt", "-p", "b", "-p", "a", "--check"]
+        );
+        // cargo fmt doesn't accept feature flags
+        let workspace = workspace(&[], &[]);
+        let mut features = clap_cargo::Features::default();
+        features.all_features = true;
+        features.features = vec!["serde".to_string()];
+        let cmd = workspace
+            .build_command("fmt", &features, &FmtOptions { check: false }, &[])
+            .unwrap();
+        assert_eq!(command_args(&cmd), ["fmt"]);
+    }
+
+    #[test]
+    fn negated_patterns_remove_crates() {
+        let workspace = workspace(
+            &[],
+            &[
+                (
+                    "narrowed",
+                    &["path:crates/*", "!pkg:util", "!path:crates/[xyz]"],
+                ),
+                ("negations", &["!pkg:a"]),
+            ],
+        );
+        assert_eq!(
+            names(&workspace.get_group_crates("narrowed").unwrap()),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+        // A group with only exclusions has nothing to exclude from
+        assert!(workspace.get_group_crates("negations").unwrap().is_empty());
+    }
+
+    #[test]
+    fn dry_run_commands_can_be_pasted_into_a_shell() {
+        assert_eq!(shell_quote("--features=a,b"), "--features=a,b");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+
+        let mut cmd = process::Command::new("cargo");
+        cmd.args(["check", "-p", "a", "--features", "serde std"])
+            .current_dir("/tmp/my workspace")
+            .env("CARGO_TERM_COLOR", "never")
+            .env_remove("RUSTFLAGS");
+        assert_eq!(
+            PlannedCommand::new(&cmd).to_shell(),
+            "cd '/tmp/my workspace' && CARGO_TERM_COLOR=never env -u RUSTFLAGS cargo check -p a --features 'serde std'"
+        );
+        // The directory is left out when it's the current one
+        cmd.current_dir(current_dir().unwrap());
+        assert!(PlannedCommand::new(&cmd)
+            .to_shell()
+            .starts_with("CARGO_TERM_COLOR=never"));
+    }
+
+    #[test]
+    fn list_json_has_the_name_and_path_of_each_crate() {
+        let workspace = workspace(&[], &[("chain", &["pkg:a", "pkg:b"]), ("leaf", &["pkg:c"])]);
+        assert_eq!(
+            workspace.groups_json(None).unwrap(),
+            serde_json::json!({
+                "groups": {
+                    "chain": [
+                        { "name": "a", "path": "crates/a" },
+                        { "name": "b", "path": "crates/b" }
+                    ],
+                    "leaf": [{ "name": "c", "path": "crates/c" }]
+                }
+            })
+        );
+        assert_eq!(
+            workspace.groups_json(Some("leaf")).unwrap(),
+            serde_json::json!({ "groups": { "leaf": [{ "name": "c", "path": "crates/c" }] } })
+        );
+    }
+
+    #[test]
+    fn failed_commands_return_their_exit_code() {
+        let workspace = workspace(&[], &[("chain", &["pkg:a"])]);
+        // Cargo exits with 101 for unknown subcommands
+        let code = workspace
+            .execute_exec("chain", &["cargo-groups-no-such-subcommand".to_string()])
+            .unwrap();
+        assert_eq!(code, 101);
+    }
+
+    #[test]
+    fn group_crates_are_cached_until_a_manifest_changes() {
+        let path = temp_dir("group-cache", &[]).join("groups-cache.json");
+        let cached_workspace = |groups: &[(&str, &[&str])]| {
+            let mut workspace = workspace(&[], groups);
+            workspace.group_cache = Some(Mutex::new(GroupCache::load(
+                path.clone(),
+                &workspace.metadata,
+            )));
+            workspace
+        };
+
+        let first = cached_workspace(&[("chain", &["pkg:a", "pkg:b"])]);
+        assert_eq!(names(&first.get_group_crates("chain").unwrap()), ["a", "b"]);
+
+        // A later run reuses the cached crates rather than matching the patterns again
+        let mut cache = GroupCache::load(path.clone(), &first.metadata);
+        cache.groups.get_mut("chain").unwrap().packages = vec![package(&first, "c").id.clone()];
+        cache.save().unwrap();
+        let second = cached_workspace(&[("chain", &["pkg:a", "pkg:b"])]);
+        assert_eq!(names(&second.get_group_crates("chain").unwrap()), ["c"]);
+
+        // Changing the group's patterns matches them again
+        let changed = cached_workspace(&[("chain", &["pkg:a"])]);
+        assert_eq!(names(&changed.get_group_crates("chain").unwrap()), ["a"]);
+
+        // So does changing a manifest
+        let mut cache = GroupCache::load(path.clone(), &first.metadata);
+        assert!(cache.groups.contains_key("chain"));
+        cache.manifests[0].1 += 1;
+        cache.save().unwrap();
+        assert!(GroupCache::load(path, &first.metadata).groups.is_empty());
+
+        assert!(workspace(&["--no-cache"], &[]).group_cache.is_none());
+    }
+
+    #[test]
+    fn missing_groups_are_the_ones_the_command_runs_on() {
+        let workspace = workspace(&[], &[("core", &["pkg:a"])]);
+        let missing =
+            |flags: &[&str]| workspace.missing_groups(&fixture_args(flags).command.unwrap());
+
+        assert_eq!(missing(&["test", "core", "nope"]), ["nope"]);
+        assert_eq!(missing(&["test"]), ["default"]);
+        assert_eq!(missing(&["tree", "core"]), Vec::<String>::new());
+        assert_eq!(missing(&["list"]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn workspaces_without_the_group_are_skipped() {
+        let root = fixture("workspace").parent().unwrap().to_path_buf();
+        let argv = |command: &[&str]| -> Vec<String> {
+            ["cargo", "groups", "--workspaces-glob", "*"]
+                .iter()
+                .chain(command)
+                .map(ToString::to_string)
+                .collect()
+        };
+
+        // Only the root-package fixture defines a `member` group
+        assert_eq!(
+            run_in_workspaces(&root, "*", &argv(&["list", "member"])).unwrap(),
+            0
+        );
+        assert_eq!(
+            run_in_workspaces(&root, "*", &argv(&["list", "nope"]))
+                .unwrap_err()
+                .to_string(),
+            "None of the workspaces matching * define the groups to run on"
+        );
+    }
+
+    #[test]
+    fn artifact_dir_goes_before_the_crates_and_implies_unstable_options() {
+        let groups: &[(&str, &[&str])] = &[("chain", &["pkg:a", "pkg:b"])];
+        assert_eq!(
+            planned_args(&["build", "--artifact-dir", "dist/", "chain"], groups),
+            [
+                "build",
+                "--artifact-dir",
+                "dist/",
+                "-p",
+                "a",
+                "-Z",
+                "unstable-options"
+            ]
+        );
+        assert_eq!(
+            option_args::<BuildOptions>(&["--artifact-dir", "dist/", "-Z", "unstable-options"]),
+            ["-Z", "unstable-options"]
+        );
+    }
+
+    #[test]
+    fn artifact_dir_is_created_unless_commands_are_only_printed() {
+        let dir = temp_dir("artifact-dir", &[]).join("dist");
+        let options = |dir: &Path| {
+            CommandOptions::<BuildOptions>::parse_from([
+                "cargo-groups",
+                "--artifact-dir",
+                dir.to_str().unwrap(),
+            ])
+        };
+
+        workspace(&["--dry-run"], &[])
+            .create_artifact_dir(&options(&dir))
+            .unwrap();
+        assert!(!dir.exists());
+
+        workspace(&[], &[])
+            .create_artifact_dir(&options(&dir))
+            .unwrap();
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    fn warning_counts_are_rejected_in_per_crate_mode() {
+        let error = run_group(
+            &[
+                "publish",
+                "chain",
+                "--fail-on-warnings-count",
+                "0",
+                "--dry-run",
+            ],
+            &[("chain", &["pkg:a", "pkg:b", "pkg:c"])],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--fail-on-warnings-count can't be used in per-crate mode"
+        );
+    }
+
+    #[test]
+    fn multiple_groups_run_on_the_union_of_their_crates() {
+        let groups: &[(&str, &[&str])] = &[
+            ("core", &["pkg:a", "pkg:b"]),
+            ("lower", &["pkg:c", "pkg:b"]),
+            ("other", &["pkg:x"]),
+        ];
+        // b is only selected once, and the crates run in dependency order
+        assert_eq!(
+            selected(&["test", "core", "lower"], groups).unwrap(),
+            ["c", "b", "a"]
+        );
+        assert_eq!(
+            selected(&["test", "core", "missing"], groups)
+                .unwrap_err()
+                .to_string(),
+            "Group missing not found"
+        );
+        assert_eq!(
+            selected(&["test", "nope", "core", "missing"], groups)
+                .unwrap_err()
+                .to_string(),
+            "Groups nope, missing not found"
+        );
+    }
+
+    #[test]
+    fn all_groups_runs_on_every_group_once() {
+        let groups: &[(&str, &[&str])] = &[
+            ("core", &["pkg:a", "pkg:b"]),
+            ("lower", &["pkg:c", "pkg:b"]),
+            ("other", &["pkg:x"]),
+        ];
+        assert_eq!(
+            selected(&["test", "--all-groups"], groups).unwrap(),
+            ["c", "b", "a", "x"]
+        );
+
+        let err =
+            Args::try_parse_from(["cargo", "groups", "test", "--all-groups", "core"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn exec_passes_arguments_through_untouched() {
+        let workspace = workspace(&[], &[("chain", &["pkg:a", "pkg:b"])]);
+        let members = workspace.get_group_crates("chain").unwrap();
+        let exec_args = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+            command_args(&workspace.exec_command(&members, &args).unwrap())
+        };
+
+        assert_eq!(
+            exec_args(&["tree", "--duplicates", "--some-unknown-flag"]),
+            [
+                "tree",
+                "--duplicates",
+                "--some-unknown-flag",
+                "-p",
+                "a",
+                "-p",
+                "b"
+            ]
+        );
+        // Arguments after `--` stay after the crates
+        assert_eq!(
+            exec_args(&["run", "--bin", "server", "--", "--port", "80"]),
+            ["run", "--bin", "server", "-p", "a", "-p", "b", "--", "--port", "80"]
+        );
+    }
+
+    #[test]
+    fn arguments_after_double_dash_come_last() {
+        let groups: &[(&str, &[&str])] = &[("chain", &["pkg:a", "pkg:b"])];
+        assert_eq!(
+            planned_args(
+                &[
+                    "test",
+                    "chain",
+                    "--features",
+                    "serde",
+                    "--release",
+                    "--",
+                    "--nocapture"
+                ],
+                groups
+            ),
+            [
+                "test",
+                "--features",
+                "serde",
+                "-p",
+                "b",
+                "-p",
+                "a",
+                "--release",
+                "--",
+                "--nocapture"
+            ]
+        );
+        assert_eq!(
+            planned_args(
+                &["clippy", "chain", "--fix", "--", "-D", "warnings"],
+                groups
+            ),
+            ["clippy", "-p", "a", "--fix", "--", "-D", "warnings"]
+        );
+        // No `--` without arguments after it
+        assert_eq!(
+            planned_args(&["test", "chain"], groups),
+            ["test", "-p", "b", "-p", "a"]
+        );
+    }
+
+    #[test]
+    fn patterns_that_match_nothing_are_reported() {
+        let groups: &[(&str, &[&str])] =
+            &[("typos", &["pkg:a", "pkg:my-srvice", "path:crates/nope"])];
+        let strict = workspace(&["--strict"], groups);
+        assert_eq!(
+            strict.get_group_crates("typos").unwrap_err().to_string(),
+            "Patterns 'pkg:my-srvice', 'path:crates/nope' matched no workspace packages"
+        );
+
+        // Without --strict there's a warning and the matched crates still run
+        let workspace = workspace(&[], groups);
+        assert_eq!(names(&workspace.get_group_crates("typos").unwrap()), ["a"]);
+        let (packages, unmatched) = workspace
+            .match_patterns(&patterns(&["pkg:a", "pkg:my-srvice", "path:crates/nope"]))
+            .unwrap();
+        assert_eq!(names(&packages), ["a"]);
+        assert_eq!(unmatched, ["pkg:my-srvice", "path:crates/nope"]);
+    }
+
+    #[test]
+    fn quiet_cargo_passes_quiet() {
+        assert_eq!(
+            option_args::<CheckOptions>(&["--quiet-cargo", "--lib"]),
+            ["--quiet", "--lib"]
+        );
+    }
+
+    #[test]
+    fn crates_are_sorted_after_their_dependencies() {
+        let workspace = workspace(&[], &[]);
+        let packages = |names: &[&str]| -> Vec<&Package> {
+            names.iter().map(|name| package(&workspace, name)).collect()
+        };
+
+        let sorted = workspace
+            .sort_topologically(packages(&["a", "c", "b"]))
+            .unwrap();
+        assert_eq!(names(&sorted), ["c", "b", "a"]);
+        // a depends on c through b, which isn't in the list
+        let sorted = workspace.sort_topologically(packages(&["a", "c"])).unwrap();
+        assert_eq!(names(&sorted), ["c", "a"]);
+        // Dev-dependencies don't change the order
+        let sorted = workspace.sort_topologically(packages(&["e", "d"])).unwrap();
+        assert_eq!(names(&sorted), ["e", "d"]);
+    }
+
+    #[test]
+    fn summary_lists_each_crate_with_its_status() {
+        colored::control::set_override(false);
+        let workspace = workspace(&[], &[]);
+        let result = |name: &str, exit_code: i32, timed_out: bool, millis: u64| CrateResult {
+            package: package(&workspace, name),
+            exit_code,
+            timed_out,
+            elapsed: Duration::from_millis(millis),
+        };
+        let results = [
+            result("a", 0, false, 1250),
+            result("util", 101, false, 20),
+            result("x", 1, true, 60000),
+        ];
+
+        let mut out = Vec::new();
+        print_summary(&mut out, &results).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\n  a    ok 1.25s\n  util FAILED 0.02s\n  x    TIMED OUT 60.00s\n1 passed, 2 failed\n"
+        );
+    }
+
+    #[test]
+    fn publish_runs_on_each_crate_in_dependency_order() {
+        let workspace = workspace(&[], &[("release", &["pkg:a", "pkg:b", "pkg:c"])]);
+        let Some(Command::Publish {
+            selection,
             features,
-            options,
-        } => workspace_info.execute_on_group("clippy", &group, features, options, true)?,
-        Command::List { group: None } => workspace_info.print_groups()?,
-        Command::List { group: Some(group) } => workspace_info.print_group(&group)?,
-    };
+            mut options,
+        }) = fixture_args(&["publish", "release", "--no-verify", "--wait", "5"]).command
+        else {
+            panic!("expected a publish command");
+        };
+        assert!(options.per_crate());
+        assert!(options.sequential());
+        assert_eq!(options.delay_between_crates(), Some(Duration::from_secs(5)));
 
-    Ok(())
+        // With --dry-run, cargo checks the crates without uploading them, so there's no wait
+        options.dry_run = true;
+        assert_eq!(options.delay_between_crates(), None);
+        let members = workspace.select_crates(&selection, false).unwrap();
+        let commands: Vec<Vec<String>> = members
+            .iter()
+            .map(|member| {
+                let cmd = workspace
+                    .build_command("publish", &features, &options, &[member])
+                    .unwrap();
+                command_args(&cmd)
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                ["publish", "-p", "c", "--no-verify", "--dry-run"],
+                ["publish", "-p", "b", "--no-verify", "--dry-run"],
+                ["publish", "-p", "a", "--no-verify", "--dry-run"]
+            ]
+        );
+    }
+
+    #[test]
+    fn publish_rejects_group_jobs() {
+        let error = run_group(
+            &["publish", "release", "--group-jobs", "4", "--dry-run"],
+            &[("release", &["pkg:a", "pkg:b", "pkg:c"])],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--group-jobs can't be used with publish, which runs one crate at a time"
+        );
+    }
+
+    #[test]
+    fn list_output_without_colors_and_the_color_passed_to_cargo() {
+        colored::control::set_override(false);
+        let mut workspace = workspace(&["--color", "never"], &[("chain", &["pkg:a", "pkg:b"])]);
+        let mut out = Vec::new();
+        workspace.print_groups(&mut out, None, None).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[chain]\n  a crates/a\n  b crates/b\n"
+        );
+
+        let color = |cmd: &process::Command| {
+            cmd.get_envs()
+                .find(|(name, _)| *name == "CARGO_TERM_COLOR")
+                .and_then(|(_, value)| value.map(|value| value.to_string_lossy().into_owned()))
+        };
+        let cmd = workspace.command_for_cargo("cargo".into());
+        assert_eq!(color(&cmd).as_deref(), Some("never"));
+        workspace.color = ColorChoice::Always;
+        let cmd = workspace.command_for_cargo("cargo".into());
+        assert_eq!(color(&cmd).as_deref(), Some("always"));
+        // With auto, cargo decides for itself
+        workspace.color = ColorChoice::Auto;
+        assert_eq!(color(&workspace.command_for_cargo("cargo".into())), None);
+    }
+
+    #[test]
+    fn lockfile_flags_go_to_cargo_and_cargo_metadata() {
+        assert_eq!(
+            option_args::<CheckOptions>(&["--locked", "--offline"]),
+            ["--locked", "--offline"]
+        );
+        let args = fixture_args(&["test", "--frozen", "core"]);
+        assert_eq!(args.command.unwrap().metadata_flags(), ["--frozen"]);
+        // Commands without the common flags don't pass any
+        let args = fixture_args(&["list"]);
+        assert!(args.command.unwrap().metadata_flags().is_empty());
+    }
+
+    #[test]
+    fn target_and_profile_are_forwarded() {
+        assert_eq!(
+            option_args::<DefaultSpecificOptions>(&[
+                "--target",
+                "wasm32-unknown-unknown",
+                "--profile",
+                "ci"
+            ]),
+            ["--profile", "ci", "--target", "wasm32-unknown-unknown"]
+        );
+        let err = CommandOptions::<DefaultSpecificOptions>::try_parse_from([
+            "cargo-groups",
+            "--release",
+            "--profile",
+            "ci",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+
+        // A group's default target and release only apply when not given on the command line
+        let defaults = DefaultOptions {
+            release: true,
+            target: Some("x86_64-unknown-linux-musl".to_string()),
+            ..DefaultOptions::default()
+        };
+        let mut options = CommandOptions::<DefaultSpecificOptions>::parse_from([
+            "cargo-groups",
+            "--profile",
+            "ci",
+            "--target",
+            "wasm32-unknown-unknown",
+        ]);
+        options.apply_defaults(&defaults);
+        assert!(!options.release);
+        assert_eq!(options.target.as_deref(), Some("wasm32-unknown-unknown"));
+        let mut options = CommandOptions::<DefaultSpecificOptions>::parse_from(["cargo-groups"]);
+        options.apply_defaults(&defaults);
+        assert!(options.release);
+        assert_eq!(options.target.as_deref(), Some("x86_64-unknown-linux-musl"));
+    }
+
+    #[test]
+    fn config_file_groups_are_merged_over_the_manifest_groups() {
+        let manifest = "[workspace.metadata.groups]\ncore = [\"pkg:a\"]\nlower = [\"pkg:c\"]\n";
+        let config = "[groups]\ncore = [\"pkg:b\"]\nextra = [\"pkg:x\"]\n";
+        let groups = |files: &[(&str, &str)]| -> Result<BTreeMap<String, Vec<String>>> {
+            let dir = temp_dir("config-file", files);
+            let cargo_toml = RootCargoToml::load(&dir.join("Cargo.toml"), None)?;
+            Ok(cargo_toml
+                .workspace
+                .metadata
+                .groups
+                .into_iter()
+                .map(|(name, group)| (name, group.patterns().to_vec()))
+                .collect())
+        };
+        let group =
+            |name: &str, group_patterns: &[&str]| (name.to_string(), patterns(group_patterns));
+
+        assert_eq!(
+            groups(&[("Cargo.toml", manifest)]).unwrap(),
+            BTreeMap::from([group("core", &["pkg:a"]), group("lower", &["pkg:c"])])
+        );
+        assert_eq!(
+            groups(&[
+                ("Cargo.toml", "[workspace]\n"),
+                (".cargo-groups.toml", config)
+            ])
+            .unwrap(),
+            BTreeMap::from([group("core", &["pkg:b"]), group("extra", &["pkg:x"])])
+        );
+        // The config file's definition of a group replaces the manifest's
+        assert_eq!(
+            groups(&[("Cargo.toml", manifest), ("cargo-groups.toml", config)]).unwrap(),
+            BTreeMap::from([
+                group("core", &["pkg:b"]),
+                group("extra", &["pkg:x"]),
+                group("lower", &["pkg:c"])
+            ])
+        );
+        assert_eq!(
+            groups(&[
+                ("Cargo.toml", manifest),
+                (".cargo-groups.toml", config),
+                ("cargo-groups.toml", config)
+            ])
+            .unwrap_err()
+            .to_string(),
+            "Found both .cargo-groups.toml and cargo-groups.toml, only one of them can be used"
+        );
+    }
+
+    #[test]
+    fn cargo_is_the_one_in_the_cargo_variable() {
+        // Cargo sets CARGO when running the tests, like it does for subcommands
+        let cargo = env::var_os("CARGO").unwrap();
+        let workspace = workspace(&[], &[]);
+        assert_eq!(workspace.cargo_command().unwrap().get_program(), cargo);
+        let cmd = workspace
+            .cargo_command_with_args(&["tree".to_string()])
+            .unwrap();
+        assert_eq!(cmd.get_program(), cargo);
+    }
+
+    #[test]
+    fn deps_patterns_include_workspace_dependencies() {
+        let workspace = workspace(
+            &[],
+            &[
+                ("chain", &["deps:a"]),
+                ("fork", &["deps:x"]),
+                ("dev", &["deps:e"]),
+                ("outside", &["deps:f"]),
+            ],
+        );
+        let crates =
+            |group: &str| -> Vec<&str> { names(&workspace.get_group_crates(group).unwrap()) };
+        assert_eq!(crates("chain"), ["a", "b", "c"]);
+        assert_eq!(crates("fork"), ["x", "y", "z"]);
+        // Dev-dependencies aren't needed to build a crate
+        assert_eq!(crates("dev"), ["e"]);
+        // f depends on a util crate outside the workspace, not the workspace's util
+        assert_eq!(crates("outside"), ["f"]);
+    }
+
+    #[test]
+    fn rdeps_patterns_include_workspace_dependents() {
+        let workspace = workspace(
+            &[],
+            &[
+                ("chain", &["rdeps:c"]),
+                ("dev", &["rdeps:d"]),
+                ("top", &["rdeps:a"]),
+                ("outside", &["rdeps:util"]),
+            ],
+        );
+        let crates =
+            |group: &str| -> Vec<&str> { names(&workspace.get_group_crates(group).unwrap()) };
+        assert_eq!(crates("chain"), ["a", "b", "c"]);
+        // Dev-dependents are included, since their tests can break
+        assert_eq!(crates("dev"), ["d", "e"]);
+        assert_eq!(crates("top"), ["a"]);
+        assert_eq!(crates("outside"), ["util"]);
+    }
+
+    #[test]
+    fn run_summary_has_the_crate_count_time_and_status() {
+        colored::control::set_override(false);
+        let mut out = Vec::new();
+        print_run_summary(&mut out, 3, Duration::from_millis(4321), 0).unwrap();
+        print_run_summary(&mut out, 1, Duration::from_millis(50), 101).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Ran on 3 crates in 4.32s: ok\nRan on 1 crates in 0.05s: failed with exit code 101\n"
+        );
+    }
+
+    #[test]
+    fn default_group_is_used_without_a_group() {
+        assert_eq!(
+            selected(&["test"], &[("default", &["pkg:c"]), ("other", &["pkg:x"])]).unwrap(),
+            ["c"]
+        );
+        assert_eq!(
+            selected(&["test"], &[("other", &["pkg:x"])])
+                .unwrap_err()
+                .to_string(),
+            "No group given and no `default` group is defined. Pass a group, or define a \
+             `default` group to run on when none is given"
+        );
+    }
+
+    #[test]
+    fn invalid_group_definitions_are_rejected_when_loading() {
+        let load = |manifest: &str| {
+            let dir = temp_dir("invalid-groups", &[("Cargo.toml", manifest)]);
+            RootCargoToml::load(&dir.join("Cargo.toml"), None)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            load("[workspace.metadata.groups]\ncore = []\n"),
+            "Group core is empty, add patterns to it or remove it"
+        );
+        assert!(
+            load("[workspace.metadata.groups]\ncore = [\"pkg:a\"]\ncore = [\"pkg:b\"]\n")
+                .starts_with("Failed to parse ")
+        );
+        assert!(
+            load("[workspace.metadata.groups]\ncore = [\"pkg:a\", \"!path:crates/[a\"]\n")
+                .starts_with("Group core has an invalid pattern '!path:crates/[a': ")
+        );
+        assert_eq!(
+            load("[workspace.metadata.groups]\ncore = [\"members\", \"!group:slow\"]\n"),
+            "Group core has the pattern '!group:slow', but groups can't be excluded with `!`. \
+             Exclude the patterns of their crates instead, or pass --exclude-group"
+        );
+    }
+
+    #[test]
+    fn jobs_are_forwarded_and_must_be_positive() {
+        assert_eq!(option_args::<TestOptions>(&["-j", "4"]), ["--jobs", "4"]);
+        let err =
+            CommandOptions::<DefaultSpecificOptions>::try_parse_from(["cargo-groups", "-j", "0"])
+                .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn members_matches_every_workspace_member() {
+        let workspace = workspace(
+            &[],
+            &[
+                ("all", &["members"]),
+                ("star", &["workspace:*"]),
+                ("most", &["members", "!pkg:util", "!pkg:[xyz]"]),
+            ],
+        );
+        assert_eq!(workspace.get_group_crates("all").unwrap().len(), 10);
+        assert_eq!(workspace.get_group_crates("star").unwrap().len(), 10);
+        assert_eq!(
+            names(&workspace.get_group_crates("most").unwrap()),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+    }
+
+    #[test]
+    fn list_filters_groups_with_a_glob() {
+        colored::control::set_override(false);
+        let workspace = workspace(
+            &[],
+            &[
+                ("svc-api", &["pkg:a"]),
+                ("svc-db", &["pkg:b"]),
+                ("tools", &["pkg:x"]),
+            ],
+        );
+        assert_eq!(
+            workspace.listed_group_names(Some("svc-*")).unwrap(),
+            ["svc-api", "svc-db"]
+        );
+        assert_eq!(
+            workspace.listed_group_names(Some("tools")).unwrap(),
+            ["tools"]
+        );
+        assert_eq!(
+            workspace.listed_group_names(None).unwrap(),
+            ["svc-api", "svc-db", "tools"]
+        );
+        assert_eq!(
+            workspace
+                .listed_group_names(Some("tool"))
+                .unwrap_err()
+                .to_string(),
+            "Group tool not found"
+        );
+
+        let mut out = Vec::new();
+        workspace
+            .print_groups(&mut out, Some("web-*"), None)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "No groups matched web-*\n");
+    }
+
+    #[test]
+    fn exclude_removes_crates_from_the_selection() {
+        let groups: &[(&str, &[&str])] = &[("core", &["pkg:a", "pkg:b", "pkg:c"])];
+        assert_eq!(
+            selected(&["test", "core", "--exclude", "b"], groups).unwrap(),
+            ["c", "a"]
+        );
+        assert_eq!(
+            selected(
+                &["test", "core", "--exclude", "[ab]", "--exclude", "c"],
+                groups
+            )
+            .unwrap_err()
+            .to_string(),
+            "No crates left in core after exclusions"
+        );
+    }
+
+    #[test]
+    fn group_env_is_set_on_the_commands() {
+        let metadata: Metadata = toml::from_str(
+            r#"
+            [groups]
+            plain = ["pkg:a"]
+            db = { patterns = ["pkg:b"], env = { SQLX_OFFLINE = "true", RUST_LOG = "info" } }
+            debug = { patterns = ["pkg:c"], env = { RUST_LOG = "debug" } }
+            "#,
+        )
+        .unwrap();
+        let mut workspace = workspace(&[], &[]);
+        workspace.cargo_toml.workspace.metadata.groups = metadata.groups;
+        let env = |groups: &[&str]| -> Vec<(String, String)> {
+            let mut cmd = process::Command::new("cargo");
+            workspace.add_group_env(&mut cmd, &patterns(groups));
+            cmd.get_envs()
+                .map(|(key, value)| {
+                    (
+                        key.to_string_lossy().into_owned(),
+                        value.unwrap().to_string_lossy().into_owned(),
+                    )
+                })
+                .collect()
+        };
+        let var = |key: &str, value: &str| (key.to_string(), value.to_string());
+
+        assert!(env(&["plain"]).is_empty());
+        assert_eq!(
+            env(&["plain", "db"]),
+            [var("RUST_LOG", "info"), var("SQLX_OFFLINE", "true")]
+        );
+        // A later group's value wins
+        assert_eq!(
+            env(&["db", "debug"]),
+            [var("RUST_LOG", "debug"), var("SQLX_OFFLINE", "true")]
+        );
+    }
+
+    #[test]
+    fn groups_are_lists_of_patterns_or_tables_with_a_description() {
+        colored::control::set_override(false);
+        let metadata: Metadata = toml::from_str(
+            r#"
+            [groups]
+            plain = ["pkg:a"]
+            described = { patterns = ["pkg:b", "pkg:c"], description = "The lower crates" }
+            "#,
+        )
+        .unwrap();
+        let plain = &metadata.groups["plain"];
+        assert!(matches!(plain, GroupDefinition::Patterns(_)));
+        assert_eq!(plain.patterns(), ["pkg:a"]);
+        assert_eq!(plain.description(), None);
+        let described = &metadata.groups["described"];
+        assert!(matches!(described, GroupDefinition::Table { .. }));
+        assert_eq!(described.patterns(), ["pkg:b", "pkg:c"]);
+        assert_eq!(described.description(), Some("The lower crates"));
+        assert!(toml::from_str::<Metadata>("[groups]\nbad = { description = \"x\" }\n").is_err());
+
+        let mut workspace = workspace(&[], &[]);
+        workspace.cargo_toml.workspace.metadata.groups = metadata.groups;
+        let mut out = Vec::new();
+        workspace.print_groups(&mut out, None, None).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[described] The lower crates\n  b crates/b\n  c crates/c\n[plain]\n  a crates/a\n"
+        );
+        let mut out = Vec::new();
+        workspace.print_group_descriptions(&mut out, None).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "described: The lower crates\nplain\n"
+        );
+    }
+
+    #[test]
+    fn tree_shows_the_dependencies_inside_the_group() {
+        colored::control::set_override(false);
+        let workspace = workspace(
+            &[],
+            &[
+                ("fork", &["pkg:x", "pkg:y", "pkg:z"]),
+                ("chain", &["pkg:a", "pkg:c"]),
+            ],
+        );
+        let tree = |group: &str| {
+            let mut out = Vec::new();
+            workspace.print_tree(&mut out, group).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            tree("fork"),
+            "[fork]\nx crates/x\n├── y crates/y\n└── z crates/z\n"
+        );
+        // Only the edges between crates of the group are shown, so a and c aren't
+        // connected without b
+        assert_eq!(tree("chain"), "[chain]\na crates/a\nc crates/c\n");
+    }
+
+    #[test]
+    fn watched_dirs_are_the_group_crates_dirs() {
+        let workspace = workspace(&[], &[("chain", &["pkg:a", "pkg:b", "pkg:c"])]);
+        let mut dirs = workspace.watched_dirs("chain").unwrap();
+        dirs.sort();
+
+        let crates = Path::new(workspace.metadata.workspace_root.as_str()).join("crates");
+        assert_eq!(dirs, [crates.join("a"), crates.join("b"), crates.join("c")]);
+    }
 }